@@ -1,10 +1,10 @@
-use std::{io, fs::File, borrow::Cow, cmp::Ordering, collections::{btree_map, BTreeMap}, fmt::Write as _, path::PathBuf, str};
+use std::{io, fs::File, cmp::Ordering, collections::{btree_map, BTreeMap}, fmt::Write as _, path::PathBuf, str};
 use anyhow::{anyhow, bail, ensure, Context as _};
 use bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
 use clap::Parser;
 use rusqlite::Connection;
 use base64::{display::Base64Display, engine::general_purpose::STANDARD};
-use encoding_rs::SHIFT_JIS;
+use blume::{sjis, stcm2::format};
 
 const STCM2_MAGIC: &[u8] = b"STCM2";
 const STCM2_TAG_LENGTH: usize = 32 - STCM2_MAGIC.len();
@@ -173,8 +173,16 @@ enum Args {
     File { file: PathBuf }
 }
 
-fn decode_sjis(buf: &[u8]) -> anyhow::Result<Cow<'_, str>> {
-    SHIFT_JIS.decode_without_bom_handling_and_without_replacement(buf).context("unknown character hit")
+// lines with an address in the global data instead of a pointer to their own data, like 212
+fn global_string(stcm2: &Stcm2, params: &[Parameter]) -> Option<(u32, Bytes)> {
+    let &[Parameter::Value(value)] = params else { return None };
+    let global = format::Stcm2 { tag: stcm2.tag.clone(), global_data: stcm2.global_data.clone(), actions: BTreeMap::new() };
+    let (s, _) = decode_string(0, global.global_string(value)?).ok()?;
+    Some((value - GLOBAL_DATA_OFFSET as u32, s))
+}
+
+fn decode_sjis(buf: &[u8]) -> anyhow::Result<String> {
+    Ok(sjis::decode(buf)?)
 }
 
 fn main() -> anyhow::Result<()> {
@@ -256,6 +264,10 @@ fn main() -> anyhow::Result<()> {
                 ensure!(tail.is_empty());
                 print!("line \"{}\"", decode_sjis(&s)?);
             },
+            Action { call: false, opcode: Action::OP_LINE, ref params, .. } if global_string(&stcm2, params).is_some() => {
+                let (offset, s) = global_string(&stcm2, params).unwrap();
+                print!("line [global+{offset}] \"{}\"", decode_sjis(&s)?);
+            },
             Action { call: false, opcode: Action::OP_CHOICE, ref params, ref data, .. } if matches!(params[..], [Parameter::LocalPointer(0), Parameter::Value(v)] if v & 0xFF000000 == 0xFF000000) => {
                 let [Parameter::LocalPointer(0), Parameter::Value(v)] = params[..] else { unreachable!() };
                 let (s, tail) = decode_string(0, data.clone())?;
@@ -283,7 +295,7 @@ fn main() -> anyhow::Result<()> {
                     if params.iter().map(|p| match p { Parameter::LocalPointer(0) => 1, _ => 0 }).sum::<usize>() == 1 {
                         if let Ok((s, tail)) = decode_string(0, data.clone()) {
                             if tail.is_empty() {
-                                if let Some(s) = SHIFT_JIS.decode_without_bom_handling_and_without_replacement(&s) {
+                                if let Ok(s) = sjis::decode(&s) {
                                     print!(", \"{s}\"");
                                     break 'printdata; // basically a goto
                                 }
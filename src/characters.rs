@@ -0,0 +1,227 @@
+//! The character registry: japanese and english names of speakers, for patching, cleanup,
+//! LLM metadata and the web view.
+
+use std::{borrow::Cow, fmt::Display, iter};
+
+use anyhow::{anyhow, ensure};
+use clap::{Parser, Subcommand};
+use rusqlite::Connection;
+
+use crate::markup::Markup;
+
+// speakers that aren't characters
+const UNKNOWN: (&str, &str) = ("？？？", "???");
+const VOICE_SUFFIX: (&str, &str) = ("の声", "'s voice");
+
+#[derive(Clone, Debug, Default)]
+pub struct Character {
+    pub jpspeaker: String,
+    pub enspeaker: String,
+    pub gender: String,
+    pub jpfull: Option<String>,
+    pub enfull: Option<String>,
+    pub aliases: Vec<(String, String)>,
+    // other english spellings of the speaker name, like in older translations
+    pub spellings: Vec<String>
+}
+
+impl Display for Character {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Name: {} ({}) | Gender: {}",
+            self.enfull.as_deref().unwrap_or(&self.enspeaker),
+            self.jpfull.as_deref().unwrap_or(&self.jpspeaker),
+            self.gender
+        )?;
+
+        if !self.aliases.is_empty() {
+            f.write_str(" | Aliases: ")?;
+            let mut aliases = self.aliases.iter().peekable();
+            while let Some((jp, en)) = aliases.next() {
+                write!(f, "{en} ({jp})")?;
+                if aliases.peek().is_some() {
+                    f.write_str(", ")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PartialEq for Character {
+    fn eq(&self, other: &Self) -> bool {
+        self.jpspeaker.eq(&other.jpspeaker)
+    }
+}
+
+impl Eq for Character {}
+
+impl std::hash::Hash for Character {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.jpspeaker.hash(state)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EnSpeaker<'a> {
+    Str(Cow<'a, str>),
+    Character(&'a Character)
+}
+
+impl Display for EnSpeaker<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnSpeaker::Str(s) => Display::fmt(s, f),
+            EnSpeaker::Character(c) => Display::fmt(&c.enspeaker, f)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    pub characters: Vec<Character>,
+    // japanese and english names of the player, as set up in the markup
+    placeholders: Vec<(String, String)>
+}
+
+impl Registry {
+    pub fn load(db: &Connection) -> anyhow::Result<Self> {
+        let mut characters = db.prepare("SELECT jpspeaker, enspeaker, gender, jpfull, enfull FROM characters ORDER BY rowid")?
+            .query_map((), |row| Ok(Character {
+                jpspeaker: row.get(0)?,
+                enspeaker: row.get(1)?,
+                gender: row.get(2)?,
+                jpfull: row.get(3)?,
+                enfull: row.get(4)?,
+                aliases: Vec::new(),
+                spellings: Vec::new()
+            }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut stmt = db.prepare("SELECT jpspeaker, jp, en FROM characteraliases ORDER BY rowid")?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            let (jpspeaker, jp, en) = <(String, String, String)>::try_from(row)?;
+            if let Some(c) = characters.iter_mut().find(|c| c.jpspeaker == jpspeaker) {
+                c.aliases.push((jp, en));
+            }
+        }
+        let mut stmt = db.prepare("SELECT jpspeaker, en FROM characterspellings ORDER BY rowid")?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            let (jpspeaker, en) = <(String, String)>::try_from(row)?;
+            if let Some(c) = characters.iter_mut().find(|c| c.jpspeaker == jpspeaker) {
+                c.spellings.push(en);
+            }
+        }
+        let placeholders = Markup::load(db)?.placeholders().map(|(_, jp, en)| (jp.to_owned(), en.to_owned())).collect();
+        Ok(Self { characters, placeholders })
+    }
+
+    /// English name of a speaker as it appears in the script. `X's voice` is derived from `X`. The
+    /// player's placeholder name is known without registering it, in case `player_name_jp` changed.
+    pub fn decode(&self, jpspeaker: &str) -> anyhow::Result<EnSpeaker<'_>> {
+        if jpspeaker == UNKNOWN.0 {
+            return Ok(EnSpeaker::Str(UNKNOWN.1.into()));
+        }
+        for c in self.characters.iter() {
+            if c.jpspeaker == jpspeaker {
+                return Ok(EnSpeaker::Character(c));
+            }
+
+            if jpspeaker.strip_prefix(c.jpspeaker.as_str()).is_some_and(|s| s == VOICE_SUFFIX.0) {
+                return Ok(EnSpeaker::Str((c.enspeaker.clone() + VOICE_SUFFIX.1).into()));
+            }
+        }
+        for (jp, en) in self.placeholders.iter() {
+            if jpspeaker == jp {
+                return Ok(EnSpeaker::Str(en.clone().into()));
+            }
+
+            if jpspeaker.strip_prefix(jp.as_str()).is_some_and(|s| s == VOICE_SUFFIX.0) {
+                return Ok(EnSpeaker::Str((en.clone() + VOICE_SUFFIX.1).into()));
+            }
+        }
+        Err(anyhow!("unknown speaker {jpspeaker}; add them with the character command"))
+    }
+
+    /// Every english speaker name with its japanese counterpart, including voices and other spellings.
+    pub fn speakers(&self) -> impl Iterator<Item = (String, String)> + '_ {
+        [(UNKNOWN.1.to_owned(), UNKNOWN.0.to_owned())].into_iter()
+            .chain(self.characters.iter().flat_map(|c| iter::once(&c.enspeaker).chain(c.spellings.iter()).flat_map(|en| [
+                (en.clone(), c.jpspeaker.clone()),
+                (en.clone() + VOICE_SUFFIX.1, c.jpspeaker.clone() + VOICE_SUFFIX.0)
+            ])))
+    }
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>
+}
+
+#[derive(Clone, Subcommand)]
+enum Mode {
+    /// List the registered characters
+    List,
+    /// Add a character or change their names
+    Set {
+        jpspeaker: String,
+        enspeaker: String,
+        #[arg(long)]
+        gender: String,
+        #[arg(long)]
+        jpfull: Option<String>,
+        #[arg(long)]
+        enfull: Option<String>
+    },
+    /// Remove a character, their aliases and spellings
+    Remove { jpspeaker: String },
+    /// Add another name a character is called by in dialogue
+    Alias { jpspeaker: String, jp: String, en: String },
+    /// Remove an alias
+    Unalias { jpspeaker: String, jp: String },
+    /// Add another english spelling of a speaker name, which cleanup changes back to the character
+    Spelling { jpspeaker: String, en: String },
+    /// Remove a spelling
+    Unspelling { en: String }
+}
+
+pub fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let tx = db.transaction()?;
+    match args.mode.unwrap_or(Mode::List) {
+        Mode::List => {
+            for c in Registry::load(&tx)?.characters {
+                println!("{} = {} | {c}", c.jpspeaker, c.enspeaker);
+            }
+        },
+        Mode::Set { jpspeaker, enspeaker, gender, jpfull, enfull } => {
+            tx.execute("
+                INSERT INTO characters(jpspeaker, enspeaker, gender, jpfull, enfull) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT(jpspeaker) DO UPDATE SET enspeaker = ?2, gender = ?3, jpfull = ?4, enfull = ?5
+            ", (jpspeaker, enspeaker, gender, jpfull, enfull))?;
+        },
+        Mode::Remove { jpspeaker } => {
+            tx.execute("DELETE FROM characteraliases WHERE jpspeaker = ?", (&jpspeaker,))?;
+            tx.execute("DELETE FROM characterspellings WHERE jpspeaker = ?", (&jpspeaker,))?;
+            let n = tx.execute("DELETE FROM characters WHERE jpspeaker = ?", (&jpspeaker,))?;
+            ensure!(n > 0, "no character {jpspeaker}");
+        },
+        Mode::Alias { jpspeaker, jp, en } => {
+            tx.execute("INSERT OR REPLACE INTO characteraliases(jpspeaker, jp, en) VALUES (?, ?, ?)", (jpspeaker, jp, en))?;
+        },
+        Mode::Unalias { jpspeaker, jp } => {
+            let n = tx.execute("DELETE FROM characteraliases WHERE jpspeaker = ? AND jp = ?", (&jpspeaker, &jp))?;
+            ensure!(n > 0, "{jpspeaker} has no alias {jp}");
+        },
+        Mode::Spelling { jpspeaker, en } => {
+            tx.execute("INSERT OR REPLACE INTO characterspellings(en, jpspeaker) VALUES (?, ?)", (en, jpspeaker))?;
+        },
+        Mode::Unspelling { en } => {
+            let n = tx.execute("DELETE FROM characterspellings WHERE en = ?", (&en,))?;
+            ensure!(n > 0, "no spelling {en}");
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
@@ -1,4 +1,6 @@
 use rusqlite::Connection;
+
+use crate::{glyphs::Glyphs, markup::{self, Markup}};
 use clap::Parser;
 
 #[derive(Parser)]
@@ -18,6 +20,8 @@ pub fn run(db: Connection, args: Args) -> anyhow::Result<()> {
         WHERE lines.scriptid = ? AND translations.session = 'google'
     ")?;
     let mut rows = stmt.query((args.script_id,))?;
+    let glyphs = Glyphs::load(&db)?;
+    let markup = Markup::load(&db)?;
 
     while let Some(row) = rows.next()? {
         let (scriptid, address, line, google): (u32, u32, String, String) = row.try_into()?;
@@ -34,6 +38,15 @@ pub fn run(db: Connection, args: Args) -> anyhow::Result<()> {
                 println!("{scriptid}, {address} is weird3\n{line}\n{google}\n");
             }
         }
+
+        if let Err(e) = markup::validate(&line, &markup.restore(&google)) {
+            println!("{scriptid}, {address} {e}\n{line}\n{google}\n");
+        }
+
+        let unsupported = glyphs.unsupported(&google);
+        if !unsupported.is_empty() {
+            println!("{scriptid}, {address} has characters not in shift_jis: {unsupported}\n{google}\n");
+        }
     }
 
     Ok(())
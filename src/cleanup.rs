@@ -1,4 +1,6 @@
 use rusqlite::Connection;
+
+use crate::{characters::Registry, markup::Markup};
 use clap::Parser;
 
 #[derive(Parser)]
@@ -16,23 +18,9 @@ const REPLACEMENTS: &[(&str, &str)] = &[
     ("）", ")")
 ];
 
+// speakers that are neither characters nor unknown
 const SPEAKERS: &[(&str, &str)] = &[
-    ("Narrator", ""),
-    ("???", "？？？"), 
-    ("Ilia", "イリヤ"),
-    ("Richard", "リチャード"),
-    ("Daniela", "ダニエラ"),
-    ("Victor", "ヴィクトル"),
-    ("Mary", "#Name[1]"),
-    ("Auguste", "オーギュスト"),
-    ("Jacob", "ヤコブ"),
-    ("Auguste's Voice", "オーギュストの声"),
-    ("Virginia", "バージニア"),
-    ("Klaus", "クラウス"),
-    ("Stefan", "ステファン"),
-    ("Relm", "レルム"),
-    ("Gerald", "ジェラルド"),
-    ("Conrad", "コンラッド")
+    ("Narrator", "")
 ];
 
 pub fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
@@ -54,12 +42,19 @@ pub fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
         let mut stmt = tx.prepare("
             UPDATE lines
             SET speaker = ?
-            WHERE speaker = ?
+            WHERE speaker = ? COLLATE NOCASE
         ")?;
 
         for &(orig, new) in SPEAKERS.iter() {
             stmt.execute((new, orig))?;
         }
+        // before the registry, which also knows the player by their placeholder name
+        for (code, _, en) in Markup::load(&tx)?.placeholders() {
+            stmt.execute((code, en))?;
+        }
+        for (orig, new) in Registry::load(&tx)?.speakers() {
+            stmt.execute((new, orig))?;
+        }
     }
 
     tx.commit()?;
@@ -1,4 +1,7 @@
-use rusqlite::Connection;
+use std::str::FromStr;
+
+use anyhow::Context as _;
+use rusqlite::{Connection, OptionalExtension as _};
 use clap::Parser;
 
 #[derive(Parser)]
@@ -33,3 +36,17 @@ pub fn run(db: Connection, args: Args) -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// Reads a config option, if it is set.
+pub fn get<T>(db: &Connection, name: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static
+{
+    let value = db.query_row(
+        "SELECT value FROM config WHERE name = ?",
+        (name,),
+        |row| row.get::<_, String>(0)
+    ).optional()?;
+    value.map(|v| v.parse().with_context(|| format!("bad value for {name}: {v}"))).transpose()
+}
@@ -0,0 +1,181 @@
+//! Glyph widths of the game font, for wrapping lines and checking that they fit in the text box.
+
+use std::{collections::HashMap, fs::{self, File}, path::PathBuf};
+
+use anyhow::{bail, ensure, Context as _};
+use clap::{Parser, Subcommand};
+use rusqlite::Connection;
+
+use crate::{config, glyphs::Glyphs, markup::Markup};
+
+/// The game prints a debug message if a line is over 45 halfwidth characters, however narrow the font.
+pub const MAX_LINE_CELLS: usize = 45;
+
+/// Widths in the unit of the glyph table. Without one, halfwidth cells are used, so that
+/// halfwidth characters are 1 wide, fullwidth characters 2 and the text box 45.
+#[derive(Clone, Debug)]
+pub struct Widths {
+    // by Shift_JIS bytes
+    glyphs: HashMap<Vec<u8>, usize>,
+    halfwidth: usize,
+    fullwidth: usize,
+    pub textbox: usize,
+    pub markup: Markup
+}
+
+impl Default for Widths {
+    fn default() -> Self {
+        Self { glyphs: HashMap::new(), halfwidth: 1, fullwidth: 2, textbox: MAX_LINE_CELLS, markup: Markup::default() }
+    }
+}
+
+impl Widths {
+    /// Reads the glyph table and the `font_halfwidth`, `font_fullwidth` and `textbox_width` options.
+    /// Remapped characters get the width of the code they are remapped to.
+    pub fn load(db: &Connection) -> anyhow::Result<Self> {
+        let default = Self::default();
+        let remap = Glyphs::load(db)?;
+        let mut glyphs = HashMap::new();
+        let mut stmt = db.prepare("SELECT glyph, width FROM glyphwidths")?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            let (glyph, width) = <(String, usize)>::try_from(row)?;
+            glyphs.insert(remap.encode(&glyph)?, width);
+        }
+        Ok(Self {
+            glyphs,
+            halfwidth: config::get(db, "font_halfwidth")?.unwrap_or(default.halfwidth),
+            fullwidth: config::get(db, "font_fullwidth")?.unwrap_or(default.fullwidth),
+            textbox: config::get(db, "textbox_width")?.unwrap_or(default.textbox),
+            markup: Markup::load(db)?
+        })
+    }
+
+    /// Width of one Shift_JIS character. Characters missing from the table get the default for their size.
+    pub fn glyph(&self, rep: &[u8]) -> usize {
+        self.glyphs.get(rep).copied().unwrap_or(if rep.len() == 1 { self.halfwidth } else { self.fullwidth })
+    }
+
+    /// Width of a markup code, which is known in halfwidth cells.
+    pub fn code(&self, code: &str) -> usize {
+        self.code_cells(code) * self.halfwidth
+    }
+
+    /// Width of a markup code in halfwidth cells, with the player's name at its widest.
+    pub fn code_cells(&self, code: &str) -> usize {
+        self.markup.width(code)
+    }
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>
+}
+
+#[derive(Clone, Subcommand)]
+enum Mode {
+    /// Show the width model
+    Show,
+    /// Read glyph widths from a file with a character (or U+XXXX) and a width on each line
+    Import { input: PathBuf },
+    /// Measure glyph widths from an image of the font laid out in a grid
+    Measure {
+        image: PathBuf,
+        #[arg(help = "file with the characters of the grid in order, row by row")]
+        charset: PathBuf,
+        #[arg(long, help = "size of a grid cell, like 24x24")]
+        cell: String,
+        #[arg(long, default_value_t = 0, help = "pixels added after each glyph")]
+        spacing: usize
+    }
+}
+
+pub fn parse_glyph(s: &str) -> anyhow::Result<String> {
+    if let Some(hex) = s.strip_prefix("U+") {
+        let c = char::from_u32(u32::from_str_radix(hex, 16)?).with_context(|| format!("bad code point {s}"))?;
+        return Ok(c.to_string());
+    }
+    let mut chars = s.chars();
+    let (Some(c), None) = (chars.next(), chars.next()) else { bail!("not a single character: {s}") };
+    Ok(c.to_string())
+}
+
+// width of the inked part of each cell, row by row; None for empty cells
+fn measure(image: PathBuf, cell_width: usize, cell_height: usize) -> anyhow::Result<Vec<Option<usize>>> {
+    let mut decoder = png::Decoder::new(File::open(image)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buf)?;
+    let (color, _) = reader.output_color_type();
+    let channels = color.samples();
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let pixel = |x: usize, y: usize| &buf[(y*width + x)*channels..][..channels];
+
+    // ink is anything opaque, or anything unlike the top left pixel without alpha
+    let background = pixel(0, 0).to_owned();
+    let inked = |x, y| match color {
+        png::ColorType::Rgba | png::ColorType::GrayscaleAlpha => pixel(x, y)[channels-1] > 0,
+        _ => pixel(x, y) != background
+    };
+
+    let mut cells = Vec::new();
+    for row in 0..height / cell_height {
+        for col in 0..width / cell_width {
+            let right = (0..cell_width).rev().find(|&dx| (0..cell_height).any(|dy| inked(col*cell_width + dx, row*cell_height + dy)));
+            cells.push(right.map(|dx| dx + 1));
+        }
+    }
+    Ok(cells)
+}
+
+pub fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let tx = db.transaction()?;
+    match args.mode.unwrap_or(Mode::Show) {
+        Mode::Show => {
+            let widths = Widths::load(&tx)?;
+            println!(
+                "{} glyphs, halfwidth {}, fullwidth {}, text box {}",
+                widths.glyphs.len(), widths.halfwidth, widths.fullwidth, widths.textbox
+            );
+        },
+        Mode::Import { input } => {
+            let mut stmt = tx.prepare("INSERT OR REPLACE INTO glyphwidths(glyph, width) VALUES (?, ?)")?;
+            let mut n = 0;
+            for (i, line) in fs::read_to_string(input)?.lines().enumerate() {
+                let line = line.trim_end_matches(['\r', '\n']);
+                if line.trim().is_empty() || line.starts_with('#') { continue }
+                let (glyph, width) = line.rsplit_once([' ', '\t']).with_context(|| format!("line {}: expected a glyph and a width", i+1))?;
+                let glyph = parse_glyph(glyph.trim_end_matches([' ', '\t'])).with_context(|| format!("line {}", i+1))?;
+                let width = width.parse::<usize>().with_context(|| format!("line {}: bad width", i+1))?;
+                stmt.execute((glyph, width))?;
+                n += 1;
+            }
+            println!("imported {n} glyphs");
+        },
+        Mode::Measure { image, charset, cell, spacing } => {
+            let (w, h) = cell.split_once('x').context("cell size should be like 24x24")?;
+            let (w, h) = (w.parse()?, h.parse()?);
+            ensure!(w > 0 && h > 0, "empty cells");
+            let cells = measure(image, w, h)?;
+            let chars = fs::read_to_string(charset)?.chars().filter(|c| *c != '\n' && *c != '\r').collect::<Vec<_>>();
+            ensure!(chars.len() <= cells.len(), "{} characters, but the image only has {} cells", chars.len(), cells.len());
+
+            let mut stmt = tx.prepare("INSERT OR REPLACE INTO glyphwidths(glyph, width) VALUES (?, ?)")?;
+            let mut empty = Vec::new();
+            for (c, cell) in chars.iter().zip(cells) {
+                match cell {
+                    Some(width) => { stmt.execute((c.to_string(), width + spacing))?; },
+                    None => empty.push(*c)
+                }
+            }
+            println!("measured {} glyphs", chars.len() - empty.len());
+            if !empty.is_empty() {
+                println!("empty cells, import these by hand: {:?}", empty);
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
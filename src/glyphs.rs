@@ -0,0 +1,191 @@
+//! Remapping of characters that Shift_JIS doesn't have, either to spare codes (to be paired with
+//! a font edit) or to ASCII stand-ins.
+
+use std::collections::HashMap;
+
+use anyhow::{ensure, Context as _};
+use clap::{ArgGroup, Parser, Subcommand};
+use rusqlite::Connection;
+
+use crate::{font, sjis::{self, Encoded}};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    // Shift_JIS bytes of a spare code point
+    Code([u8; 2]),
+    Ascii(String)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Glyphs {
+    map: HashMap<char, Target>
+}
+
+impl Glyphs {
+    pub fn load(db: &Connection) -> anyhow::Result<Self> {
+        let mut map = HashMap::new();
+        let mut stmt = db.prepare("SELECT glyph, code, ascii FROM glyphmap")?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            let (glyph, code, ascii) = <(String, Option<u16>, Option<String>)>::try_from(row)?;
+            let glyph = font::parse_glyph(&glyph)?.chars().next().unwrap();
+            let target = match (code, ascii) {
+                (Some(code), None) => Target::Code(code.to_be_bytes()),
+                (None, Some(ascii)) => Target::Ascii(ascii),
+                _ => unreachable!("checked by the table")
+            };
+            map.insert(glyph, target);
+        }
+        Ok(Self { map })
+    }
+
+    // the encoded text, and the characters that are not in Shift_JIS-2004 and that are not in the game's font
+    fn scan(&self, text: &str) -> (Vec<u8>, String, String) {
+        let mut enc = Vec::with_capacity(text.len());
+        let (mut unknown, mut not_in_font) = (String::new(), String::new());
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let len = match self.map.get(&c) {
+                Some(Target::Code(code)) => { enc.extend_from_slice(code); c.len_utf8() },
+                Some(Target::Ascii(ascii)) => { enc.extend_from_slice(ascii.as_bytes()); c.len_utf8() },
+                None => match sjis::encode_next(rest) {
+                    (Encoded::Char(rep), len) => { enc.extend_from_slice(rep); len },
+                    (Encoded::NotInFont(_), len) => { push_unique(&mut not_in_font, &rest[..len]); len },
+                    (Encoded::Unknown, len) => { push_unique(&mut unknown, &rest[..len]); len }
+                }
+            };
+            rest = &rest[len..];
+        }
+        (enc, unknown, not_in_font)
+    }
+
+    /// Encodes text to Shift_JIS, remapping the characters in the table. Fails with every
+    /// character that is not in Shift_JIS-2004 or not in the game's font, and not remapped.
+    pub fn encode(&self, text: &str) -> anyhow::Result<Vec<u8>> {
+        let (enc, unknown, not_in_font) = self.scan(text);
+        let mut problems = Vec::new();
+        if !unknown.is_empty() {
+            problems.push(format!("characters not in shift_jis-2004: {unknown}"));
+        }
+        if !not_in_font.is_empty() {
+            problems.push(format!("characters not in the game font: {not_in_font}"));
+        }
+        ensure!(problems.is_empty(), "{}; remap them with the glyph command", problems.join("; "));
+        Ok(enc)
+    }
+
+    /// Characters of the text that can't be encoded.
+    pub fn unsupported(&self, text: &str) -> String {
+        let (_, unknown, not_in_font) = self.scan(text);
+        unknown + &not_in_font
+    }
+}
+
+fn push_unique(s: &mut String, c: &str) {
+    if !s.contains(c) {
+        s.push_str(c);
+    }
+}
+
+// spare codes are whole double-byte Shift_JIS characters
+fn parse_code(s: &str) -> anyhow::Result<u16> {
+    let code = u16::from_str_radix(s.trim_start_matches("0x"), 16).with_context(|| format!("bad code {s}"))?;
+    let [lead, trail] = code.to_be_bytes();
+    ensure!(
+        matches!(lead, 0x81..=0x9F | 0xE0..=0xFC) && matches!(trail, 0x40..=0x7E | 0x80..=0xFC),
+        "{code:04X} is not a double-byte shift_jis code"
+    );
+    Ok(code)
+}
+
+#[derive(Parser)]
+pub struct Args {
+    #[command(subcommand)]
+    mode: Option<Mode>
+}
+
+#[derive(Clone, Subcommand)]
+enum Mode {
+    /// List the remapped characters
+    List,
+    /// Remap a character (or U+XXXX) to a spare Shift_JIS code or to ASCII
+    #[command(group(ArgGroup::new("target").required(true)))]
+    Set {
+        glyph: String,
+        #[arg(long, group = "target", help = "Shift_JIS code in hex, like F040, whose glyph is replaced in the font")]
+        code: Option<String>,
+        #[arg(long, group = "target", help = "ASCII text to write instead")]
+        ascii: Option<String>
+    },
+    /// Stop remapping a character
+    Remove { glyph: String }
+}
+
+pub fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let tx = db.transaction()?;
+    match args.mode.unwrap_or(Mode::List) {
+        Mode::List => {
+            let mut stmt = tx.prepare("SELECT glyph, code, ascii FROM glyphmap ORDER BY code IS NULL, code, glyph")?;
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                let (glyph, code, ascii) = <(String, Option<u16>, Option<String>)>::try_from(row)?;
+                match (code, ascii) {
+                    (Some(code), _) => println!("{glyph} = {code:04X}"),
+                    (_, Some(ascii)) => println!("{glyph} = {ascii:?}"),
+                    _ => unreachable!()
+                }
+            }
+        },
+        Mode::Set { glyph, code, ascii } => {
+            let glyph = font::parse_glyph(&glyph)?;
+            let code = code.as_deref().map(parse_code).transpose()?;
+            if let Some(code) = code {
+                // unassigned codes are spare
+                if let Ok(current) = sjis::decode(&code.to_be_bytes()) {
+                    eprintln!("warning: {code:04X} is {current}, which the font edit replaces");
+                }
+                let others = tx.prepare("SELECT glyph FROM glyphmap WHERE code = ? AND glyph != ?")?
+                    .query_map((code, &glyph), |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                ensure!(others.is_empty(), "{code:04X} is already used by {}", others.join(", "));
+            }
+            if let Some(ascii) = &ascii {
+                ensure!(ascii.is_ascii(), "not ascii: {ascii}");
+            }
+            tx.execute("INSERT OR REPLACE INTO glyphmap(glyph, code, ascii) VALUES (?, ?, ?)", (glyph, code, ascii))?;
+        },
+        Mode::Remove { glyph } => {
+            let glyph = font::parse_glyph(&glyph)?;
+            let n = tx.execute("DELETE FROM glyphmap WHERE glyph = ?", (&glyph,))?;
+            ensure!(n > 0, "{glyph} is not remapped");
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glyphs() -> Glyphs {
+        Glyphs { map: HashMap::from([('ä', Target::Code([0xF0, 0x40])), ('“', Target::Ascii("\"".to_owned()))]) }
+    }
+
+    #[test]
+    fn encode_remaps() {
+        assert_eq!(glyphs().encode("Lärm: “Hi”").unwrap(), b"L\xF0\x40rm: \"Hi\x81\x68");
+    }
+
+    #[test]
+    fn encode_em_dash_without_remapping() {
+        assert_eq!(Glyphs::default().encode("a—b").unwrap(), b"a\x81\x5Cb");
+    }
+
+    #[test]
+    fn encode_lists_every_missing_character_once() {
+        let err = glyphs().encode("öä😀öü😀").unwrap_err().to_string();
+        assert!(err.contains("not in shift_jis-2004: 😀;"), "{err}");
+        assert!(err.contains("not in the game font: öü;"), "{err}");
+    }
+}
@@ -1,3 +1,4 @@
+use anyhow::ensure;
 use rusqlite::Connection;
 use clap::Parser;
 
@@ -27,5 +28,218 @@ pub fn run(mut db: Connection, _args: Args) -> anyhow::Result<()> {
         ) WITHOUT ROWID, STRICT;
     ")?;
     tx.commit()?;
+    upgrade(&mut db)
+}
+
+/// Schema changes since the first version of the database, oldest first. A database stores how many
+/// it has in `user_version`, and gets the rest when it is opened. Each one may find its tables
+/// already there, from databases made before it was recorded.
+const MIGRATIONS: &[&str] = &[
+    "
+        CREATE TABLE IF NOT EXISTS voices(
+            scriptid INTEGER,
+            address INTEGER,
+            voice INTEGER NOT NULL,
+            FOREIGN KEY(scriptid, address) REFERENCES lines(scriptid, address),
+            PRIMARY KEY(scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS relocations(
+            opcode INTEGER,
+            offset INTEGER,
+            PRIMARY KEY(opcode, offset)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS choices(
+            scriptid INTEGER REFERENCES scripts(id),
+            address INTEGER,
+            prompt TEXT NOT NULL,
+            choice INTEGER NOT NULL,
+            option TEXT NOT NULL,
+            PRIMARY KEY(scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+        CREATE TABLE IF NOT EXISTS choicetranslations(
+            session TEXT,
+            scriptid INTEGER,
+            address INTEGER,
+            translation TEXT NOT NULL,
+            FOREIGN KEY(scriptid, address) REFERENCES choices(scriptid, address),
+            PRIMARY KEY(session, scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS staletranslations(
+            session TEXT,
+            scriptid INTEGER,
+            address INTEGER,
+            oldline TEXT NOT NULL,
+            FOREIGN KEY(session, scriptid, address) REFERENCES translations(session, scriptid, address) ON DELETE CASCADE,
+            PRIMARY KEY(session, scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS layouts(
+            scriptid INTEGER,
+            address INTEGER,
+            widths TEXT NOT NULL,
+            pagebreak INTEGER NOT NULL,
+            FOREIGN KEY(scriptid, address) REFERENCES lines(scriptid, address),
+            PRIMARY KEY(scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS strings(
+            scriptid INTEGER REFERENCES scripts(id),
+            address INTEGER,
+            param INTEGER,
+            opcode INTEGER NOT NULL,
+            call INTEGER NOT NULL,
+            text TEXT NOT NULL,
+            PRIMARY KEY(scriptid, address, param)
+        ) WITHOUT ROWID, STRICT;
+        CREATE TABLE IF NOT EXISTS stringtranslations(
+            session TEXT,
+            scriptid INTEGER,
+            address INTEGER,
+            param INTEGER,
+            translation TEXT NOT NULL,
+            FOREIGN KEY(scriptid, address, param) REFERENCES strings(scriptid, address, param),
+            PRIMARY KEY(session, scriptid, address, param)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS scenes(
+            scriptid INTEGER PRIMARY KEY REFERENCES scripts(id),
+            title TEXT,
+            route TEXT,
+            predecessor INTEGER REFERENCES scripts(id),
+            successor INTEGER REFERENCES scripts(id)
+        ) STRICT;
+        CREATE TABLE IF NOT EXISTS scenejumps(
+            opcode INTEGER,
+            param INTEGER,
+            PRIMARY KEY(opcode, param)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS characters(
+            jpspeaker TEXT PRIMARY KEY,
+            enspeaker TEXT NOT NULL,
+            gender TEXT NOT NULL,
+            jpfull TEXT,
+            enfull TEXT
+        ) STRICT;
+        CREATE TABLE IF NOT EXISTS characteraliases(
+            jpspeaker TEXT REFERENCES characters(jpspeaker),
+            jp TEXT,
+            en TEXT NOT NULL,
+            PRIMARY KEY(jpspeaker, jp)
+        ) STRICT;
+        INSERT OR IGNORE INTO characters(jpspeaker, enspeaker, gender, jpfull, enfull) VALUES
+            ('メアリ', 'Mary', 'Female', NULL, NULL),
+            ('ダニエラ', 'Daniela', 'Female', 'ダニエラ・ブランクーシ', 'Daniela Brancusi'),
+            ('ヴィクトル', 'Victor', 'Male', 'ヴィクトル・フリードリヒ', 'Victor Friedrich'),
+            ('オーギュスト', 'Auguste', 'Male', 'オーギュスト・ミュラー', 'Auguste Müller'),
+            ('イリヤ', 'Ilya', 'Female', 'イリヤ・カンテミール', 'Ilya Cantemir'),
+            ('リチャード', 'Richard', 'Male', 'リチャード・カンテミール', 'Richard Cantemir'),
+            ('ヤコブ', 'Jacob', 'Male', 'ヤコブ・カンテミール', 'Jacob Cantemir'),
+            ('バージニア', 'Virginia', 'Female', 'バージニア・モレノ', 'Virginia Moreno'),
+            ('ジェラルド', 'Gerald', 'Male', 'ジェラルド・ヴィルベルヴィント', 'Gerald Villbervint'),
+            ('コンラッド', 'Conrad', 'Male', 'コンラッド・バートリ', 'Conrad Bathory'),
+            ('バラージュ', 'Balazs', 'Male', 'バラージュ・フォン・イシュトヴァーン', 'Balazs von Ishtvaan'),
+            ('クラウス', 'Claus', 'Male', NULL, NULL),
+            ('ステファン', 'Stefan', 'Male', NULL, NULL),
+            ('レルム', 'Lärm', 'Male', NULL, NULL),
+            ('レオ', 'Leo', 'Male', NULL, NULL),
+            ('ギルベルト', 'Gilbert', 'Male', NULL, NULL),
+            ('エミリオ', 'Emilio', 'Male', NULL, NULL),
+            ('ディメトリオ', 'Demetrio', 'Male', NULL, NULL),
+            ('オリヴィア', 'Olivia', 'Female', NULL, NULL),
+            ('ヴォルマー', 'Volmer', 'Male', NULL, NULL),
+            ('ダンケルハイト', 'Dunkelheit', 'Male', NULL, NULL),
+            ('人狼', 'Werewolf', 'Male', NULL, NULL),
+            ('黒衣の男性', 'Man in black', 'Male', NULL, NULL);
+        INSERT OR IGNORE INTO characteraliases(jpspeaker, jp, en) VALUES
+            ('リチャード', 'お兄ちゃん', 'Onii-chan'),
+            ('ヤコブ', '村長', 'mayor');
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS glyphwidths(glyph TEXT PRIMARY KEY, width INTEGER NOT NULL) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS nopagesplit(
+            scriptid INTEGER,
+            address INTEGER,
+            FOREIGN KEY(scriptid, address) REFERENCES lines(scriptid, address),
+            PRIMARY KEY(scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS glyphmap(
+            glyph TEXT PRIMARY KEY,
+            code INTEGER UNIQUE,
+            ascii TEXT,
+            CHECK((code IS NULL) != (ascii IS NULL))
+        ) WITHOUT ROWID, STRICT;
+        INSERT OR IGNORE INTO glyphmap(glyph, code, ascii) VALUES
+            ('ä', NULL, 'a'),
+            ('ö', NULL, 'o'),
+            ('ü', NULL, 'u'),
+            ('é', NULL, 'e'),
+            ('–', NULL, '-'),
+            ('‘', NULL, ''''),
+            ('’', NULL, ''''),
+            ('“', NULL, '\"'),
+            ('”', NULL, '\"');
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS stalechoicetranslations(
+            session TEXT,
+            scriptid INTEGER,
+            address INTEGER,
+            oldoption TEXT NOT NULL,
+            FOREIGN KEY(session, scriptid, address) REFERENCES choicetranslations(session, scriptid, address) ON DELETE CASCADE,
+            PRIMARY KEY(session, scriptid, address)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS stringparams(
+            opcode INTEGER,
+            call INTEGER,
+            param INTEGER,
+            PRIMARY KEY(opcode, call, param)
+        ) WITHOUT ROWID, STRICT;
+    ",
+    "
+        CREATE TABLE IF NOT EXISTS characterspellings(
+            en TEXT PRIMARY KEY,
+            jpspeaker TEXT NOT NULL REFERENCES characters(jpspeaker)
+        ) STRICT;
+        INSERT OR IGNORE INTO characterspellings(en, jpspeaker) VALUES
+            ('Ilia', 'イリヤ'),
+            ('Klaus', 'クラウス'),
+            ('Relm', 'レルム');
+    ",
+    "
+        DELETE FROM glyphmap WHERE glyph = '—' AND ascii = '--';
+    "
+];
+
+/// Brings the schema of an existing database up to date.
+pub fn upgrade(db: &mut Connection) -> anyhow::Result<()> {
+    let version = db.pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))?;
+    ensure!(version <= MIGRATIONS.len(), "the database is from a newer version of blume (schema {version}, this one knows {})", MIGRATIONS.len());
+    if version == MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let tx = db.transaction()?;
+    for migration in &MIGRATIONS[version..] {
+        tx.execute_batch(migration)?;
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
     Ok(())
 }
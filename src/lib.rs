@@ -0,0 +1,7 @@
+//! The parts of blume that the tools in `src/bin` share with it.
+
+pub mod sjis;
+
+pub mod stcm2 {
+    pub mod format;
+}
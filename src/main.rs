@@ -11,9 +11,16 @@ mod web;
 mod cleanup;
 mod checkpunct;
 mod script;
+mod markup;
+mod scene;
+mod characters;
+mod font;
+mod glyphs;
+mod nosplit;
 // mod iso;
 
 use std::path::PathBuf;
+use blume::sjis;
 use rusqlite::{Connection, OpenFlags};
 use clap::{Parser, Subcommand};
 
@@ -39,7 +46,12 @@ enum Command {
     Web(web::Args),
     Cleanup(cleanup::Args),
     Checkpunct(checkpunct::Args),
-    Script(script::Args)
+    Script(script::Args),
+    Scene(scene::Args),
+    Character(characters::Args),
+    Font(font::Args),
+    Glyph(glyphs::Args),
+    Nosplit(nosplit::Args)
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -49,7 +61,7 @@ async fn main() -> anyhow::Result<()> {
     #[cfg(feature = "web")]
     tracing_subscriber::fmt::init();
 
-    let db = match args.command {
+    let mut db = match args.command {
         Init(_) => Connection::open(args.file)?,
         // open without creating if not init
         _ => Connection::open_with_flags(
@@ -58,6 +70,10 @@ async fn main() -> anyhow::Result<()> {
         )?
     };
     db.pragma_update(None, "foreign_keys", true)?;
+    // init makes the tables first
+    if !matches!(args.command, Init(_)) {
+        init::upgrade(&mut db)?;
+    }
 
     use Command::*;
     match args.command {
@@ -71,6 +87,11 @@ async fn main() -> anyhow::Result<()> {
         Web(margs) => web::run(db, margs).await,
         Cleanup(margs) => cleanup::run(db, margs),
         Checkpunct(margs) => checkpunct::run(db, margs),
-        Script(margs) => script::run(db, margs)
+        Script(margs) => script::run(db, margs),
+        Scene(margs) => scene::run(db, margs),
+        Character(margs) => characters::run(db, margs),
+        Font(margs) => font::run(db, margs),
+        Glyph(margs) => glyphs::run(db, margs),
+        Nosplit(margs) => nosplit::run(db, margs)
     }
 }
@@ -0,0 +1,209 @@
+//! Inline markup codes in dialogue text, like `#Name[1]`.
+
+use std::{borrow::Cow, collections::BTreeSet, iter};
+
+use rusqlite::Connection;
+
+use crate::config;
+
+pub const PLAYER_NAME: &str = "#Name[1]";
+
+#[derive(Clone, Copy, Debug)]
+pub struct CodeInfo {
+    pub code: &'static str,
+    pub meaning: &'static str,
+    // in halfwidth cells, unless set up otherwise
+    pub width: usize,
+    // what the code is replaced with before translation (japanese) and after (english), unless set up otherwise
+    pub placeholder: Option<(&'static str, &'static str)>
+}
+
+pub static CODES: &[CodeInfo] = &[
+    CodeInfo {
+        code: PLAYER_NAME,
+        meaning: "player name",
+        width: 10,
+        placeholder: Some(("メアリ", "Mary"))
+    }
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Code(&'a str)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "translate"), allow(dead_code))]
+pub enum Lang {
+    Ja,
+    En
+}
+
+/// Length of the code at the start of `input`, if there is one.
+///
+/// Codes are `#` followed by an ASCII identifier and a bracketed argument. This works on
+/// Shift_JIS as well, since neither `#` nor letters can be the second byte of a fullwidth
+/// character; callers must only ask at character boundaries.
+pub fn code_len(input: &[u8]) -> Option<usize> {
+    let rest = input.strip_prefix(b"#")?;
+    let ident = rest.iter().take_while(|c| c.is_ascii_alphanumeric() || **c == b'_').count();
+    if ident == 0 || !rest[ident..].starts_with(b"[") { return None }
+    let arg = rest[ident+1..].iter().take_while(|&&c| c != b']' && c.is_ascii() && !c.is_ascii_control()).count();
+    if rest.get(ident+1+arg) != Some(&b']') { return None }
+    Some(1 + ident + 1 + arg + 1)
+}
+
+pub fn info(code: &str) -> Option<&'static CodeInfo> {
+    CODES.iter().find(|c| c.code == code)
+}
+
+pub fn parse(mut s: &str) -> impl Iterator<Item = Segment<'_>> {
+    iter::from_fn(move || {
+        if s.is_empty() { return None }
+        if let Some(len) = code_len(s.as_bytes()) {
+            let (code, rest) = s.split_at(len);
+            s = rest;
+            return Some(Segment::Code(code));
+        }
+        let mut end = s.len();
+        for (i, _) in s.match_indices('#').skip_while(|&(i, _)| i == 0) {
+            if code_len(&s.as_bytes()[i..]).is_some() {
+                end = i;
+                break;
+            }
+        }
+        let (text, rest) = s.split_at(end);
+        s = rest;
+        Some(Segment::Text(text))
+    })
+}
+
+pub fn codes(s: &str) -> BTreeSet<&str> {
+    parse(s).filter_map(|seg| match seg {
+        Segment::Code(c) => Some(c),
+        Segment::Text(_) => None
+    }).collect()
+}
+
+/// Checks that a translation uses the same codes as its source.
+pub fn validate(source: &str, translation: &str) -> Result<(), String> {
+    let source = codes(source);
+    let translation = codes(translation);
+    if source == translation {
+        return Ok(());
+    }
+    let describe = |c: &&str| match info(c) {
+        Some(info) => format!("{c} ({})", info.meaning),
+        None => format!("{c} (unknown)")
+    };
+    let missing = source.difference(&translation).map(describe).collect::<Vec<_>>();
+    let extra = translation.difference(&source).map(describe).collect::<Vec<_>>();
+    Err(format!("codes differ: missing [{}], extra [{}]", missing.join(", "), extra.join(", ")))
+}
+
+#[derive(Clone, Debug)]
+struct Code {
+    code: &'static str,
+    width: usize,
+    placeholder: Option<(String, String)>
+}
+
+/// The codes as set up for this project. The player's name can be changed with the `player_name_jp`
+/// (the name translators see), `player_name_en` (the name in translations) and `player_name_width`
+/// (the widest name a player may type, in halfwidth cells) options.
+#[derive(Clone, Debug)]
+pub struct Markup {
+    codes: Vec<Code>
+}
+
+impl Default for Markup {
+    fn default() -> Self {
+        Self {
+            codes: CODES.iter().map(|c| Code {
+                code: c.code,
+                width: c.width,
+                placeholder: c.placeholder.map(|(jp, en)| (jp.to_owned(), en.to_owned()))
+            }).collect()
+        }
+    }
+}
+
+impl Markup {
+    pub fn load(db: &Connection) -> anyhow::Result<Self> {
+        let mut markup = Self::default();
+        let player = markup.codes.iter_mut().find(|c| c.code == PLAYER_NAME).unwrap();
+        if let Some(width) = config::get(db, "player_name_width")? {
+            player.width = width;
+        }
+        if let Some((jp, en)) = player.placeholder.as_mut() {
+            if let Some(name) = config::get(db, "player_name_jp")? { *jp = name; }
+            if let Some(name) = config::get(db, "player_name_en")? { *en = name; }
+        }
+        Ok(markup)
+    }
+
+    /// Display width of a code in halfwidth cells. Unknown codes are assumed to be printed as is.
+    pub fn width(&self, code: &str) -> usize {
+        self.codes.iter().find(|c| c.code == code).map_or(code.len(), |c| c.width)
+    }
+
+    /// Codes with their japanese and english placeholders.
+    pub fn placeholders(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.codes.iter().filter_map(|c| c.placeholder.as_ref().map(|(jp, en)| (c.code, jp.as_str(), en.as_str())))
+    }
+
+    /// Replaces codes that have a placeholder with its natural text for translation. Other codes,
+    /// recognised or not, are left in the text as is, and it's up to the translator to keep them;
+    /// `validate` catches translations that don't.
+    #[cfg_attr(not(feature = "translate"), allow(dead_code))]
+    pub fn protect<'a>(&self, s: &'a str, lang: Lang) -> Cow<'a, str> {
+        if !self.placeholders().any(|(code, _, _)| codes(s).contains(code)) {
+            return Cow::Borrowed(s);
+        }
+        Cow::Owned(parse(s).map(|seg| match seg {
+            Segment::Code(c) => match (self.placeholders().find(|&(code, _, _)| code == c), lang) {
+                (Some((_, jp, _)), Lang::Ja) => jp,
+                (Some((_, _, en)), Lang::En) => en,
+                (None, _) => c
+            },
+            Segment::Text(t) => t
+        }).collect())
+    }
+
+    /// Turns the english placeholders in a translation back into codes. Only whole words are
+    /// replaced, so a player named Mary doesn't turn Maryland into a code.
+    pub fn restore<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        let mut s = Cow::Borrowed(s);
+        for (code, _, en) in self.placeholders() {
+            let word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+            let (first, last) = (en.chars().next(), en.chars().next_back());
+            let mut restored = String::new();
+            let mut rest = 0;
+            for (i, _) in s.match_indices(en) {
+                if word(first) && word(s[..i].chars().next_back()) || word(last) && word(s[i + en.len()..].chars().next()) {
+                    continue;
+                }
+                restored.push_str(&s[rest..i]);
+                restored.push_str(code);
+                rest = i + en.len();
+            }
+            if rest > 0 {
+                restored.push_str(&s[rest..]);
+                s = Cow::Owned(restored);
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_whole_words() {
+        let markup = Markup::default();
+        assert_eq!(markup.restore("Mary? Mary's hat, not Maryland or Rosemary."), "#Name[1]? #Name[1]'s hat, not Maryland or Rosemary.");
+    }
+}
@@ -0,0 +1,40 @@
+use rusqlite::Connection;
+use clap::Parser;
+
+#[derive(Parser)]
+pub struct Args {
+    script_id: u32,
+    #[arg(help = "addresses of the lines to keep on one page when patching; lists them if none are given")]
+    addresses: Vec<u32>,
+    #[arg(long, help = "allow splitting these lines again")]
+    remove: bool
+}
+
+pub fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let tx = db.transaction()?;
+
+    if args.addresses.is_empty() {
+        let mut stmt = tx.prepare("
+            SELECT address, line FROM nopagesplit JOIN lines USING (scriptid, address)
+            WHERE scriptid = ?
+            ORDER BY address
+        ")?;
+        let mut rows = stmt.query((args.script_id,))?;
+        while let Some(row) = rows.next()? {
+            let (address, line): (u32, String) = row.try_into()?;
+            println!("{address}: {line}");
+        }
+    } else {
+        let mut stmt = tx.prepare(if args.remove {
+            "DELETE FROM nopagesplit WHERE scriptid = ? AND address = ?"
+        } else {
+            "INSERT OR IGNORE INTO nopagesplit(scriptid, address) VALUES (?, ?)"
+        })?;
+        for address in args.addresses {
+            stmt.execute((args.script_id, address))?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::fmt::Display;
+
+use rusqlite::{Connection, OptionalExtension as _};
+use clap::Parser;
+
+#[derive(Clone, Debug, Default)]
+pub struct Scene {
+    pub scriptid: u32,
+    pub title: Option<String>,
+    pub route: Option<String>,
+    pub predecessor: Option<u32>,
+    pub successor: Option<u32>
+}
+
+impl TryFrom<&rusqlite::Row<'_>> for Scene {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &rusqlite::Row<'_>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            scriptid: row.get(0)?,
+            title: row.get(1)?,
+            route: row.get(2)?,
+            predecessor: row.get(3)?,
+            successor: row.get(4)?
+        })
+    }
+}
+
+impl Display for Scene {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.scriptid)?;
+        if let Some(ref title) = self.title {
+            write!(f, " \"{title}\"")?;
+        }
+        if let Some(ref route) = self.route {
+            write!(f, " (route {route})")?;
+        }
+        if let Some(predecessor) = self.predecessor {
+            write!(f, ", after {predecessor}")?;
+        }
+        if let Some(successor) = self.successor {
+            write!(f, ", before {successor}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn get(db: &Connection, scriptid: u32) -> rusqlite::Result<Option<Scene>> {
+    db.query_row(
+        "SELECT scriptid, title, route, predecessor, successor FROM scenes WHERE scriptid = ?",
+        (scriptid,),
+        |row| Scene::try_from(row)
+    ).optional()
+}
+
+#[derive(Parser)]
+pub struct Args {
+    scriptid: Option<u32>,
+    #[arg(long, requires = "scriptid")]
+    title: Option<String>,
+    #[arg(long, requires = "scriptid")]
+    route: Option<String>,
+    #[arg(long, requires = "scriptid")]
+    predecessor: Option<u32>,
+    #[arg(long, requires = "scriptid")]
+    successor: Option<u32>
+}
+
+pub fn run(db: Connection, args: Args) -> anyhow::Result<()> {
+    let Some(scriptid) = args.scriptid else {
+        let mut stmt = db.prepare("SELECT scriptid, title, route, predecessor, successor FROM scenes ORDER BY scriptid")?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            println!("{}", Scene::try_from(row)?);
+        }
+        return Ok(());
+    };
+
+    db.execute("INSERT OR IGNORE INTO scenes(scriptid) VALUES (?)", (scriptid,))?;
+    if let Some(title) = args.title {
+        db.execute("UPDATE scenes SET title = ? WHERE scriptid = ?", (title, scriptid))?;
+    }
+    if let Some(route) = args.route {
+        db.execute("UPDATE scenes SET route = ? WHERE scriptid = ?", (route, scriptid))?;
+    }
+    if let Some(predecessor) = args.predecessor {
+        db.execute("UPDATE scenes SET predecessor = ? WHERE scriptid = ?", (predecessor, scriptid))?;
+    }
+    if let Some(successor) = args.successor {
+        db.execute("UPDATE scenes SET successor = ? WHERE scriptid = ?", (successor, scriptid))?;
+    }
+
+    if let Some(scene) = get(&db, scriptid)? {
+        println!("{scene}");
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! Shift_JIS-2004 (JIS X 0213) codec, built from the mapping table in the repository.
+//!
+//! Like `encoding_rs`, bytes below 0x80 are ASCII rather than JIS-Roman, so `\` and `~` stay
+//! themselves. The game's font only has JIS X 0208 and the NEC special characters of row 13,
+//! so characters added by JIS X 0213 decode, but are reported when encoding.
+
+use std::{collections::HashMap, fmt::Display};
+
+use once_cell::sync::Lazy;
+
+static MAPPING: &str = include_str!("../sjis-0213-2004-std.txt");
+
+#[derive(Clone, Copy, Debug)]
+struct Code {
+    bytes: [u8; 2],
+    len: usize,
+    in_font: bool
+}
+
+impl Code {
+    fn rep(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+struct Table {
+    decode: HashMap<Vec<u8>, String>,
+    encode: HashMap<String, Code>
+}
+
+fn parse_unicode(s: &str) -> Option<String> {
+    s.strip_prefix("U+")?
+        .split('+')
+        .map(|cp| u32::from_str_radix(cp, 16).ok().and_then(char::from_u32))
+        .collect()
+}
+
+static TABLE: Lazy<Table> = Lazy::new(|| {
+    let mut table = Table { decode: HashMap::new(), encode: HashMap::new() };
+    for line in MAPPING.lines().filter(|l| !l.starts_with('#')) {
+        let mut fields = line.split('\t');
+        let (Some(code), Some(unicode)) = (fields.next(), fields.next()) else { continue };
+        let notes = fields.collect::<Vec<_>>().join("\t");
+        let code = u16::from_str_radix(code.trim_start_matches("0x"), 16).expect("bad code in mapping table");
+        let code = match code.to_be_bytes() {
+            [0, b] => Code { bytes: [b, 0], len: 1, in_font: true },
+            bytes => Code {
+                bytes,
+                len: 2,
+                in_font: !(notes.contains("[2000]") || notes.contains("[2004]")) || (0x8740..=0x879C).contains(&code)
+            }
+        };
+        if code.len == 1 && code.bytes[0] < 0x80 {
+            let ascii = (code.bytes[0] as char).to_string();
+            table.decode.insert(code.rep().to_vec(), ascii.clone());
+            table.encode.insert(ascii, code);
+            continue;
+        }
+
+        // alternatives are encoded as well, and the Windows one decodes first, to give the same
+        // text as CP932 did before (like U+FF5E for the wave dash, and U+FFE0 for the cent sign)
+        let alternative = |note| notes.split_once(note).and_then(|(_, u)| parse_unicode(u.split_whitespace().next()?));
+        let (windows, fullwidth) = (alternative("Windows: "), alternative("Fullwidth: "));
+        let unicode = parse_unicode(unicode);
+        let mut texts = windows.iter().chain(unicode.iter()).chain(fullwidth.iter()).filter(|s| !s.is_ascii());
+        let Some(text) = texts.next() else { continue };
+        table.decode.insert(code.rep().to_vec(), text.clone());
+        table.encode.entry(text.clone()).or_insert(code);
+        for text in texts {
+            table.encode.entry(text.clone()).or_insert(code);
+        }
+    }
+    table
+});
+
+#[derive(Clone, Debug)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub bytes: Vec<u8>
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown shift_jis-2004 character {:02X?} at byte {}", self.bytes, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn decode_next(b: &[u8]) -> Result<(&'static str, usize), usize> {
+    let len = if matches!(b[0], 0x81..=0x9F | 0xE0..=0xFC) { 2 } else { 1 };
+    match b.get(..len).and_then(|rep| TABLE.decode.get(rep)) {
+        Some(s) => Ok((s, len)),
+        None => Err(len.min(b.len()))
+    }
+}
+
+pub fn decode(mut b: &[u8]) -> Result<String, DecodeError> {
+    let mut s = String::with_capacity(b.len());
+    let mut offset = 0;
+    while !b.is_empty() {
+        let len = match decode_next(b) {
+            Ok((c, len)) => { s.push_str(c); len },
+            Err(len) => return Err(DecodeError { offset, bytes: b[..len].to_vec() })
+        };
+        b = &b[len..];
+        offset += len;
+    }
+    Ok(s)
+}
+
+/// Decodes with U+FFFD for unknown characters, for showing text.
+pub fn decode_lossy(mut b: &[u8]) -> String {
+    let mut s = String::with_capacity(b.len());
+    while !b.is_empty() {
+        let len = match decode_next(b) {
+            Ok((c, len)) => { s.push_str(c); len },
+            Err(len) => { s.push(char::REPLACEMENT_CHARACTER); len }
+        };
+        b = &b[len..];
+    }
+    s
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoded<'a> {
+    Char(&'a [u8]),
+    // in Shift_JIS-2004, but not in the game's font
+    NotInFont(&'a [u8]),
+    Unknown
+}
+
+/// Encodes the character (or combining sequence) at the start of the text, returning how many bytes of it were used.
+pub fn encode_next(s: &str) -> (Encoded<'static>, usize) {
+    let mut ends = s.char_indices().map(|(i, _)| i).skip(1).chain([s.len()]).take(2).collect::<Vec<_>>();
+    // sequences of two characters first
+    ends.reverse();
+    for end in ends {
+        if let Some(code) = TABLE.encode.get(&s[..end]) {
+            let rep = code.rep();
+            return (if code.in_font { Encoded::Char(rep) } else { Encoded::NotInFont(rep) }, end);
+        }
+    }
+    (Encoded::Unknown, s.chars().next().map_or(0, char::len_utf8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_signs_like_cp932() {
+        let text = decode(&[0x81, 0x60, 0x81, 0x7C, 0x81, 0x91, 0x81, 0x92, 0x81, 0xCA]).unwrap();
+        assert_eq!(text, "\u{FF5E}\u{FF0D}\u{FFE0}\u{FFE1}\u{FFE2}");
+    }
+}
@@ -1,30 +1,286 @@
+use std::{collections::{BTreeMap, BTreeSet}, panic};
+
+use anyhow::{anyhow, bail};
 use bytes::Bytes;
+use rayon::iter::{IntoParallelIterator as _, ParallelIterator as _};
 use rusqlite::{Connection, DropBehavior};
 
-use super::{Args, parse, format};
+use crate::{font::Widths, markup};
 
-pub fn analyze(mut db: Connection, args: Args) -> anyhow::Result<()> {
-    let mut tx = db.transaction()?;
-    tx.set_drop_behavior(DropBehavior::Commit);
+use super::{Args, Selection, parse::{self, Dialogue, Layout}, format::{self, Action}};
 
-    let file = tx.query_row("SELECT script FROM scripts WHERE id = ?", (args.id,), |row| Ok(Bytes::copy_from_slice(row.get_ref(0)?.as_blob()?)))?;
+#[derive(Clone, Debug, Default)]
+struct Summary {
+    lines: usize,
+    voiced: usize,
+    choices: usize,
+    unknown_codes: BTreeSet<String>
+}
 
-    let stcm2 = format::from_bytes(file)?;
+fn parse_script(file: Bytes, auto: bool, widths: &Widths) -> anyhow::Result<Option<Vec<Dialogue>>> {
+    let mut stcm2 = format::from_bytes(file)?;
+    stcm2.inline_global_lines();
 
-    let parsed = parse::parse(stcm2.actions.into_iter().filter_map(|(addr, act)| act.op(addr.orig).ok()))?;
+    if auto && !stcm2.actions.values().any(|act| !act.call && act.opcode == Action::OP_LINE) {
+        return Ok(None);
+    }
+
+    let parsed = parse::parse(stcm2.actions.into_iter().filter_map(|(addr, act)| act.op(addr.orig).ok()), widths)?;
+    Ok(Some(parsed))
+}
 
-    let mut stmt = tx.prepare("INSERT OR IGNORE INTO lines(scriptid, address, speaker, line) VALUES (?, ?, ?, ?)")?;
-    let mut n = 0;
+fn store(tx: &Connection, id: u32, parsed: Vec<Dialogue>) -> anyhow::Result<Summary> {
+    let mut stmt = tx.prepare_cached("INSERT OR IGNORE INTO lines(scriptid, address, speaker, line) VALUES (?, ?, ?, ?)")?;
+    let mut voice_stmt = tx.prepare_cached("INSERT OR IGNORE INTO voices(scriptid, address, voice) VALUES (?, ?, ?)")?;
+    let mut choice_stmt = tx.prepare_cached("INSERT OR IGNORE INTO choices(scriptid, address, prompt, choice, option) VALUES (?, ?, ?, ?, ?)")?;
+    let mut layout_stmt = tx.prepare_cached("INSERT OR REPLACE INTO layouts(scriptid, address, widths, pagebreak) VALUES (?, ?, ?, ?)")?;
+    let mut store_layout = |addr: u32, layout: Layout| layout_stmt.execute((
+        id, addr,
+        layout.widths.iter().map(usize::to_string).collect::<Vec<_>>().join(" "),
+        layout.pagebreak
+    ));
+    let mut summary = Summary::default();
     for d in parsed {
-        if let parse::Dialogue::Line { addr, speaker, line } = d {
-            stmt.execute((args.id, addr, speaker, line))?;
-        } else if let parse::Dialogue::Choice { .. } = d {
-            n += 1;
+        if let Dialogue::Line { addr, speaker, line, voice, layout } = d {
+            summary.unknown_codes.extend(markup::codes(&line).into_iter().filter(|c| markup::info(c).is_none()).map(str::to_owned));
+            stmt.execute((id, addr, speaker, line))?;
+            store_layout(addr, layout)?;
+            summary.lines += 1;
+            if let Some(voice) = voice {
+                voice_stmt.execute((id, addr, voice))?;
+                summary.voiced += 1;
+            }
+        } else if let Dialogue::Choice { addr, speaker, prompt, options, layout } = d {
+            // the prompt is translated like any other line
+            if !prompt.is_empty() {
+                stmt.execute((id, addr, speaker, &prompt))?;
+                store_layout(addr, layout)?;
+                summary.lines += 1;
+            }
+            for option in options {
+                choice_stmt.execute((id, option.addr, &prompt, option.id, option.text))?;
+            }
+            summary.choices += 1;
         }
     }
-    println!("found {n} choices");
+    Ok(summary)
+}
+
+type Parsed = Vec<(u32, anyhow::Result<Option<Vec<Dialogue>>>)>;
 
-    drop(stmt);
+fn parse_scripts(db: &Connection, ids: &Selection) -> anyhow::Result<Parsed> {
+    let auto = matches!(ids, Selection::Auto);
+    let files = super::load(db, ids)?;
+    let widths = Widths::load(db)?;
+
+    // the parser still panics on some malformed input; don't let one script take down the rest
+    Ok(files.into_par_iter()
+        .map(|(id, file)| (id, panic::catch_unwind(|| parse_script(file, auto, &widths))
+            .unwrap_or_else(|_| Err(anyhow!("parser panicked")))))
+        .collect())
+}
+
+// (speaker, line) by address, as they are stored in the lines table
+fn dialogue_lines(parsed: &[Dialogue]) -> BTreeMap<u32, (&str, &str)> {
+    parsed.iter().filter_map(|d| match d {
+        Dialogue::Line { addr, speaker, line, .. } => Some((*addr, (speaker.as_str(), line.as_str()))),
+        Dialogue::Choice { addr, speaker, prompt, .. } if !prompt.is_empty() => Some((*addr, (speaker.as_str(), prompt.as_str()))),
+        Dialogue::Choice { .. } => None
+    }).collect()
+}
+
+// (prompt, choice, option) by address, as they are stored in the choices table
+fn dialogue_choices(parsed: &[Dialogue]) -> BTreeMap<u32, (&str, u32, &str)> {
+    parsed.iter().flat_map(|d| match d {
+        Dialogue::Choice { prompt, options, .. } => options.iter().map(|o| (o.addr, (prompt.as_str(), o.id, o.text.as_str()))).collect(),
+        Dialogue::Line { .. } => Vec::new()
+    }).collect()
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Changes {
+    changed: usize,
+    added: usize,
+    orphaned: usize,
+    stale: usize
+}
+
+fn reanalyze_script(tx: &Connection, id: u32, parsed: Vec<Dialogue>, update: bool) -> anyhow::Result<Changes> {
+    let stored = tx.prepare_cached("SELECT address, speaker, line, (SELECT COUNT(*) FROM translations WHERE scriptid = lines.scriptid AND address = lines.address) FROM lines WHERE scriptid = ?")?
+        .query_map((id,), |row| Ok((row.get::<_, u32>(0)?, (row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, usize>(3)?))))?
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+    let lines = dialogue_lines(&parsed);
+
+    let mut changes = Changes::default();
+    for (&addr, &(speaker, line)) in lines.iter() {
+        match stored.get(&addr) {
+            None => {
+                println!("{id} {addr}: added\n  new: [{speaker}] {line}");
+                changes.added += 1;
+            },
+            Some((old_speaker, old_line, ntls)) if old_speaker != speaker || old_line != line => {
+                println!("{id} {addr}: changed ({ntls} translations)\n  old: [{old_speaker}] {old_line}\n  new: [{speaker}] {line}");
+                changes.changed += 1;
+                changes.stale += ntls;
+                if update {
+                    tx.execute("
+                        INSERT OR IGNORE INTO staletranslations(session, scriptid, address, oldline)
+                        SELECT session, scriptid, address, ? FROM translations WHERE scriptid = ? AND address = ?
+                    ", (old_line, id, addr))?;
+                    tx.execute("UPDATE lines SET speaker = ?, line = ? WHERE scriptid = ? AND address = ?", (speaker, line, id, addr))?;
+                }
+            },
+            Some(_) => ()
+        }
+    }
+    for (&addr, (old_speaker, old_line, ntls)) in stored.iter() {
+        if lines.contains_key(&addr) { continue }
+        changes.orphaned += 1;
+        if *ntls > 0 {
+            println!("{id} {addr}: orphaned, kept for its {ntls} translations\n  old: [{old_speaker}] {old_line}");
+        } else {
+            println!("{id} {addr}: orphaned\n  old: [{old_speaker}] {old_line}");
+            if update {
+                tx.execute("DELETE FROM voices WHERE scriptid = ? AND address = ?", (id, addr))?;
+                tx.execute("DELETE FROM layouts WHERE scriptid = ? AND address = ?", (id, addr))?;
+                tx.execute("DELETE FROM nopagesplit WHERE scriptid = ? AND address = ?", (id, addr))?;
+                tx.execute("DELETE FROM lines WHERE scriptid = ? AND address = ?", (id, addr))?;
+            }
+        }
+    }
+
+    let stored = tx.prepare_cached("SELECT address, prompt, choice, option, (SELECT COUNT(*) FROM choicetranslations WHERE scriptid = choices.scriptid AND address = choices.address) FROM choices WHERE scriptid = ?")?
+        .query_map((id,), |row| Ok((row.get::<_, u32>(0)?, (row.get::<_, String>(1)?, row.get::<_, u32>(2)?, row.get::<_, String>(3)?, row.get::<_, usize>(4)?))))?
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+    let choices = dialogue_choices(&parsed);
+
+    for (&addr, &(prompt, choice, option)) in choices.iter() {
+        match stored.get(&addr) {
+            None => {
+                println!("{id} {addr}: choice added\n  new: {option}");
+                changes.added += 1;
+            },
+            Some((old_prompt, old_choice, old_option, ntls)) if old_prompt != prompt || *old_choice != choice || old_option != option => {
+                println!("{id} {addr}: choice changed ({ntls} translations)\n  old: {old_option} ({old_choice:X}, after {old_prompt})\n  new: {option} ({choice:X}, after {prompt})");
+                changes.changed += 1;
+                changes.stale += ntls;
+                if update {
+                    tx.execute("
+                        INSERT OR IGNORE INTO stalechoicetranslations(session, scriptid, address, oldoption)
+                        SELECT session, scriptid, address, ? FROM choicetranslations WHERE scriptid = ? AND address = ?
+                    ", (old_option, id, addr))?;
+                    tx.execute("UPDATE choices SET prompt = ?, choice = ?, option = ? WHERE scriptid = ? AND address = ?", (prompt, choice, option, id, addr))?;
+                }
+            },
+            Some(_) => ()
+        }
+    }
+    for (&addr, (_, _, old_option, ntls)) in stored.iter() {
+        if choices.contains_key(&addr) { continue }
+        changes.orphaned += 1;
+        if *ntls > 0 {
+            println!("{id} {addr}: choice orphaned, kept for its {ntls} translations\n  old: {old_option}");
+        } else {
+            println!("{id} {addr}: choice orphaned\n  old: {old_option}");
+            if update {
+                tx.execute("DELETE FROM choices WHERE scriptid = ? AND address = ?", (id, addr))?;
+            }
+        }
+    }
+
+    if update {
+        tx.execute("DELETE FROM voices WHERE scriptid = ?", (id,))?;
+        store(tx, id, parsed)?;
+    }
+
+    Ok(changes)
+}
+
+pub fn reanalyze(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let mut tx = db.transaction()?;
+    tx.set_drop_behavior(DropBehavior::Commit);
+
+    let parsed = parse_scripts(&tx, &args.ids)?;
+
+    let mut total = Changes::default();
+    let mut failed = Vec::new();
+    for (id, parsed) in parsed {
+        let res = match parsed {
+            Ok(None) => continue,
+            Ok(Some(parsed)) => {
+                let mut sp = tx.savepoint()?;
+                sp.set_drop_behavior(DropBehavior::Rollback);
+                reanalyze_script(&sp, id, parsed, args.update).and_then(|changes| { sp.commit()?; Ok(changes) })
+            },
+            Err(e) => Err(e)
+        };
+        match res {
+            Ok(changes) => {
+                total.changed += changes.changed;
+                total.added += changes.added;
+                total.orphaned += changes.orphaned;
+                total.stale += changes.stale;
+            },
+            Err(e) => {
+                println!("{id}: failed: {e:#}");
+                failed.push(id);
+            }
+        }
+    }
+    println!(
+        "total: {} changed, {} added, {} orphaned, {} translations {}",
+        total.changed, total.added, total.orphaned, total.stale,
+        if args.update { "flagged as stale" } else { "would be flagged as stale (pass --update to apply)" }
+    );
+
+    if args.dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    if !failed.is_empty() {
+        bail!("{} scripts failed: {failed:?}", failed.len());
+    }
+
+    Ok(())
+}
+
+pub fn analyze(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let mut tx = db.transaction()?;
+    tx.set_drop_behavior(DropBehavior::Commit);
+
+    let parsed = parse_scripts(&tx, &args.ids)?;
+
+    let mut total = Summary::default();
+    let mut failed = Vec::new();
+    for (id, parsed) in parsed {
+        let res = match parsed {
+            Ok(None) => continue,
+            Ok(Some(parsed)) => {
+                let mut sp = tx.savepoint()?;
+                sp.set_drop_behavior(DropBehavior::Rollback);
+                store(&sp, id, parsed).and_then(|summary| { sp.commit()?; Ok(summary) })
+            },
+            Err(e) => Err(e)
+        };
+        match res {
+            Ok(summary) => {
+                println!("{id}: found {} lines ({} voiced), {} choices", summary.lines, summary.voiced, summary.choices);
+                if !summary.unknown_codes.is_empty() {
+                    println!("{id}: unknown markup codes: {:?}", summary.unknown_codes);
+                }
+                total.lines += summary.lines;
+                total.voiced += summary.voiced;
+                total.choices += summary.choices;
+            },
+            Err(e) => {
+                println!("{id}: failed: {e:#}");
+                failed.push(id);
+            }
+        }
+    }
+    println!("total: found {} lines ({} voiced), {} choices", total.lines, total.voiced, total.choices);
 
     if args.dry_run {
         tx.rollback()?;
@@ -32,5 +288,9 @@ pub fn analyze(mut db: Connection, args: Args) -> anyhow::Result<()> {
         tx.commit()?;
     }
 
+    if !failed.is_empty() {
+        bail!("{} scripts failed: {failed:?}", failed.len());
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
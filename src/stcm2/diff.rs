@@ -0,0 +1,290 @@
+use std::{collections::{HashMap, HashSet}, ops::Range};
+
+use anyhow::{bail, ensure};
+use bytes::Bytes;
+use rusqlite::{Connection, OptionalExtension as _};
+
+use crate::sjis;
+
+use super::{format::{self, Action, Address, Parameter}, pointers, verify, Args};
+
+fn is_line(act: &Action) -> bool {
+    !act.call && act.opcode == Action::OP_LINE && matches!(act.params[..], [Parameter::LocalPointer(_)])
+}
+
+fn is_yield(act: &Action) -> bool {
+    !act.call && act.opcode == Action::OP_YIELD
+}
+
+fn is_speaker(act: &Action) -> bool {
+    !act.call && act.opcode == Action::OP_SPEAKER
+}
+
+// one action as the disassembler would show it
+fn describe(act: &Action) -> String {
+    let string = |addr: u32| format::decode_string(addr, act.data.clone()).map_or_else(
+        |_| "?".to_owned(),
+        |s| format!("{:?}", sjis::decode_lossy(&s))
+    );
+    match (act.call, act.opcode, &act.params[..]) {
+        (false, Action::OP_SPEAKER, &[Parameter::LocalPointer(addr)]) => format!("speaker {}", string(addr)),
+        (false, Action::OP_LINE, &[Parameter::LocalPointer(addr)]) => format!("line {}", string(addr)),
+        (false, Action::OP_CHOICE, &[Parameter::LocalPointer(addr), Parameter::Value(id)]) => format!("choice {id:X}, {}", string(addr)),
+        (false, Action::OP_YIELD, []) => "yield".to_owned(),
+        (call, opcode, params) => {
+            let mut s = format!("{}{opcode:X}", if call { "call " } else { "raw " });
+            for &param in params {
+                match param {
+                    Parameter::Value(v) => s += &format!(", {v:X}"),
+                    Parameter::GlobalPointer(addr) => s += &format!(", [{addr:X}]"),
+                    Parameter::LocalPointer(addr) => s += &format!(", {}", string(addr))
+                }
+            }
+            s
+        }
+    }
+}
+
+// the action without its text, for checking that nothing else changed
+fn without_text(act: &Action) -> Action {
+    Action {
+        params: act.params.iter().map(|&p| match p {
+            Parameter::LocalPointer(_) => Parameter::LocalPointer(0),
+            p => p
+        }).collect(),
+        data: Bytes::new(),
+        relocs: Vec::new(),
+        ..act.clone()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Counts {
+    same: usize,
+    expected: usize,
+    unexpected: usize
+}
+
+fn diff_script(id: u32, orig: Bytes, patched: Bytes, catalog: &[(u32, u32)], strings: &HashSet<u32>) -> anyhow::Result<Counts> {
+    let mut orig = format::from_bytes(orig)?;
+    orig.mark_relocations(catalog)?;
+    // the patcher turns these into ordinary lines
+    orig.inline_global_lines();
+    let patched = format::from_bytes(patched)?;
+    let src = orig.actions.iter().collect::<Vec<_>>();
+    let new = patched.actions.iter().map(|(addr, act)| (addr.orig, act)).collect::<Vec<_>>();
+
+    // dialogue is replaced in blocks of lines and yields, and every other action is kept in order
+    let mut steps = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < src.len() && j < new.len() {
+        if is_line(src[i].1) {
+            let (src_end, new_end) = (block_end(&src, i), block_end(&new, j));
+            steps.push(Step::Block(i..src_end, j..new_end));
+            (i, j) = (src_end, new_end);
+        } else {
+            steps.push(Step::Pair(i, j));
+            i += 1;
+            j += 1;
+        }
+    }
+    let same_block = |s: &Range<usize>, n: &Range<usize>| src[s.clone()].iter().map(|a| describe(a.1)).eq(new[n.clone()].iter().map(|a| describe(a.1)));
+    let mut map = HashMap::new();
+    // the patcher points pointers to replaced lines at the start of their entry
+    let mut moved = HashMap::new();
+    for step in steps.iter() {
+        match *step {
+            Step::Pair(i, j) => { map.insert(new[j].0, *src[i].0); },
+            Step::Block(ref s, ref n) if same_block(s, n) => map.extend(new[n.clone()].iter().map(|a| a.0).zip(src[s.clone()].iter().map(|a| *a.0))),
+            Step::Block(ref s, ref n) => {
+                if !n.is_empty() {
+                    map.insert(new[n.start].0, *src[s.start].0);
+                }
+                let entry = match s.start.checked_sub(1).map(|i| src[i]) {
+                    Some((addr, act)) if is_speaker(act) => addr.orig,
+                    _ => src[s.start].0.orig
+                };
+                moved.extend(src[s.clone()].iter().map(|a| (a.0.orig, entry)));
+            }
+        }
+    }
+
+    let mut counts = Counts::default();
+    let mut unexpected = |addr: Address, msg: String| {
+        println!("! {id} {:X}.{}: {msg}", addr.orig, addr.sub);
+        counts.unexpected += 1;
+    };
+
+    for step in steps {
+        match step {
+            Step::Block(src_range, new_range) => {
+                let src_acts = &src[src_range.clone()];
+                let new_acts = &new[new_range.clone()];
+                let src_addr = *src_acts[0].0;
+                if same_block(&src_range, &new_range) {
+                    counts.same += src_acts.len();
+                    continue;
+                }
+                for (addr, act) in src_acts.iter() {
+                    println!("- {id} {:X}.{}: {}", addr.orig, addr.sub, describe(act));
+                }
+                for (sub, (_, act)) in new_acts.iter().enumerate() {
+                    println!("+ {id} {:X}.{sub}: {}", src_addr.orig, describe(act));
+                }
+                // translated lines may need more pages, never fewer, and can't go away
+                let (src_yields, new_yields) = (
+                    src_acts.iter().filter(|a| is_yield(a.1)).count(),
+                    new_acts.iter().filter(|a| is_yield(a.1)).count()
+                );
+                let src_lines = src_acts.len() - src_yields;
+                let new_lines = new_acts.len() - new_yields;
+                if new_lines == 0 {
+                    unexpected(src_addr, format!("{src_lines} lines were removed"));
+                } else if new_yields < src_yields {
+                    unexpected(src_addr, format!("{} pages became {}", src_yields + 1, new_yields + 1));
+                } else {
+                    counts.expected += 1;
+                }
+            },
+            Step::Pair(i, j) => {
+                let (&src_addr, src_act) = src[i];
+                let src_act = &{
+                    let mut act = src_act.clone();
+                    act.retarget(&moved);
+                    act
+                };
+                let (new_pos, new_act) = new[j];
+                let mut issues = Vec::new();
+                verify::check(src_addr, src_act, new_pos, new_act, &map, &mut issues);
+                if issues.is_empty() {
+                    counts.same += 1;
+                    continue;
+                }
+
+                // the text of speakers, choices and extracted strings is expected to change
+                let translatable = !src_act.call && matches!(src_act.opcode, Action::OP_SPEAKER | Action::OP_CHOICE)
+                    || strings.contains(&src_addr.orig);
+                let mut text_issues = Vec::new();
+                if translatable {
+                    verify::check(src_addr, &without_text(src_act), new_pos, &without_text(new_act), &map, &mut text_issues);
+                }
+                if translatable && text_issues.is_empty() {
+                    println!("~ {id} {:X}.{}: {} -> {}", src_addr.orig, src_addr.sub, describe(src_act), describe(new_act));
+                    counts.expected += 1;
+                } else {
+                    for issue in issues {
+                        unexpected(src_addr, format!("{}: {}", describe(src_act), issue.msg));
+                    }
+                }
+            }
+        }
+    }
+
+    for (addr, act) in src[i..].iter() {
+        unexpected(**addr, format!("{} was removed", describe(act)));
+    }
+    for &(pos, act) in new[j..].iter() {
+        unexpected(Address { orig: pos, sub: 0 }, format!("{} was added", describe(act)));
+    }
+
+    Ok(counts)
+}
+
+enum Step {
+    // an action kept in place, by index in the original and the patched script
+    Pair(usize, usize),
+    // a block of dialogue
+    Block(Range<usize>, Range<usize>)
+}
+
+// the end of the block of lines and yields starting at `start`
+fn block_end<T>(acts: &[(T, &Action)], start: usize) -> usize {
+    start + acts[start..].iter().take_while(|a| is_line(a.1) || is_yield(a.1)).count()
+}
+
+pub fn diff(db: Connection, args: Args) -> anyhow::Result<()> {
+    let catalog = pointers::catalog(&db)?;
+    let mut total = Counts::default();
+    let mut diffed = 0;
+    for id in args.ids.resolve(&db)? {
+        let patched = db.query_row("SELECT script FROM patchedscripts WHERE id = ?", (id,), |row| row.get::<_, Vec<u8>>(0)).optional()?;
+        let Some(patched) = patched else { continue };
+        let orig = db.query_row("SELECT script FROM scripts WHERE id = ?", (id,), |row| row.get::<_, Vec<u8>>(0))?;
+        let strings = db.prepare("SELECT DISTINCT address FROM strings WHERE scriptid = ?")?
+            .query_map((id,), |row| row.get(0))?
+            .collect::<Result<HashSet<u32>, _>>()?;
+
+        let counts = diff_script(id, orig.into(), patched.into(), &catalog, &strings)?;
+        println!("{id}: {} unchanged, {} expected changes, {} unexpected changes", counts.same, counts.expected, counts.unexpected);
+        total.same += counts.same;
+        total.expected += counts.expected;
+        total.unexpected += counts.unexpected;
+        diffed += 1;
+    }
+    ensure!(diffed > 0, "none of the selected scripts are patched");
+
+    if total.unexpected > 0 {
+        bail!("{} unexpected changes to non-dialogue actions", total.unexpected);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut as _;
+
+    use super::*;
+
+    fn line(text: &[u8]) -> Action {
+        let qlen = text.len() / 4 + 1;
+        let mut data = Vec::new();
+        for x in [0, qlen, 1, 4*qlen] {
+            data.put_u32_le(x as u32);
+        }
+        data.extend(text);
+        data.resize(16 + 4*qlen, 0);
+        Action { opcode: Action::OP_LINE, params: vec![Parameter::LocalPointer(0)], data: data.into(), ..Default::default() }
+    }
+
+    fn op(opcode: u32) -> Action {
+        Action { opcode, ..Default::default() }
+    }
+
+    fn script(actions: impl IntoIterator<Item = Action>) -> Bytes {
+        format::to_bytes(format::Stcm2 {
+            tag: Bytes::from_static(&[0; 27]),
+            global_data: Bytes::new(),
+            actions: actions.into_iter().enumerate().map(|(i, act)| (Address { orig: i as u32, sub: 0 }, act)).collect()
+        }).unwrap().freeze()
+    }
+
+    fn dialogue(first: &[u8]) -> Bytes {
+        script([op(0x10), line(first), line(b"\x82\xa2"), op(Action::OP_YIELD), op(0x20)])
+    }
+
+    #[test]
+    fn diff_unchanged_script() {
+        let counts = diff_script(100, dialogue(b"\x82\xa0"), dialogue(b"\x82\xa0"), &[], &HashSet::new()).unwrap();
+        assert_eq!((counts.same, counts.expected, counts.unexpected), (5, 0, 0));
+    }
+
+    #[test]
+    fn diff_changed_line() {
+        let counts = diff_script(100, dialogue(b"\x82\xa0"), dialogue(b"Ah."), &[], &HashSet::new()).unwrap();
+        assert_eq!((counts.same, counts.expected, counts.unexpected), (2, 1, 0));
+    }
+
+    #[test]
+    fn diff_removed_block() {
+        let patched = script([op(0x10), op(Action::OP_YIELD), op(0x20)]);
+        let counts = diff_script(100, dialogue(b"\x82\xa0"), patched, &[], &HashSet::new()).unwrap();
+        assert_eq!((counts.same, counts.expected, counts.unexpected), (2, 0, 1));
+    }
+
+    #[test]
+    fn diff_changed_action() {
+        let patched = script([op(0x11), line(b"\x82\xa0"), line(b"\x82\xa2"), op(Action::OP_YIELD), op(0x20)]);
+        let counts = diff_script(100, dialogue(b"\x82\xa0"), patched, &[], &HashSet::new()).unwrap();
+        assert_eq!((counts.same, counts.expected, counts.unexpected), (4, 0, 1));
+    }
+}
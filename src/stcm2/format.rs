@@ -1,4 +1,4 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
 
 use anyhow::{anyhow, bail, ensure, Context as _};
 use bytes::{Buf as _, BufMut as _, Bytes, BytesMut};
@@ -33,6 +33,10 @@ pub enum Operation {
         id: u32,
         s: Bytes
     },
+    Voice {
+        addr: u32,
+        id: u32
+    },
     Unknown(Action)
 }
 
@@ -86,7 +90,9 @@ pub struct Action {
     pub call: bool,
     pub opcode: u32,
     pub params: Vec<Parameter>,
-    pub data: Bytes
+    pub data: Bytes,
+    // pointers hidden in data: (offset into data, original address of target action)
+    pub relocs: Vec<(u32, u32)>
 }
 
 pub fn decode_string(addr: u32, mut str: Bytes) -> anyhow::Result<Bytes> {
@@ -110,6 +116,26 @@ pub fn decode_string(addr: u32, mut str: Bytes) -> anyhow::Result<Bytes> {
     Ok(str)
 }
 
+/// Reads the string block at `addr` in action data, returning the string and the length of the block.
+pub fn string_at(data: &Bytes, addr: u32) -> anyhow::Result<(Bytes, usize)> {
+    let mut str = data.slice(usize::try_from(addr)?..);
+    ensure!(str.len() >= 16, "string header out of bounds");
+
+    ensure!(str.get_u32_le() == 0, "string magic isn't 0");
+    let qlen = str.get_u32_le();
+    ensure!(str.get_u32_le() == 1, "string magic isn't 1");
+    let len = str.get_u32_le();
+    ensure!(len/4 == qlen, "len and qlen are inconsistent: len = {len}, qlen = {qlen}");
+    ensure!(str.len() >= len as usize, "string out of bounds");
+
+    str.truncate(len.try_into()?);
+    while let Some(0) = str.last() {
+        str.truncate(str.len()-1);
+    }
+
+    Ok((str, 16 + len as usize))
+}
+
 #[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
 struct DecodeUnimplemented;
@@ -127,7 +153,8 @@ impl Action {
     pub const OP_YIELD: u32 = 0xd3;
     pub const OP_LINE: u32 = 0xd2;
     pub const OP_CHOICE: u32 = 0xe7;
-    
+    pub const OP_VOICE: u32 = 0x7a;
+
     pub fn op(self, orig_addr: u32) -> anyhow::Result<Operation> {
         match self {
             Action { call: true, .. } => Ok(Operation::Unknown(self)),
@@ -143,10 +170,85 @@ impl Action {
                 let &[Parameter::LocalPointer(addr), Parameter::Value(id)] = &params[..] else { bail!("bad choice: params = {params:08X?}"); };
                 Ok(Operation::Choice { addr: orig_addr, id, s: decode_string(addr, data.clone())? })
             },
+            Action { opcode: Self::OP_VOICE, ref params, .. } => {
+                let &[Parameter::Value(id), ..] = &params[..] else { bail!("bad voice: params = {params:08X?}"); };
+                Ok(Operation::Voice { addr: orig_addr, id })
+            },
             _ => Ok(Operation::Unknown(self))
         }
     }
 
+    /// Replaces the strings behind local pointer parameters, keyed by parameter index, with the given
+    /// encoded string blocks, moving everything after them in the data.
+    pub fn replace_strings(&mut self, new: &BTreeMap<usize, Bytes>) -> anyhow::Result<()> {
+        // (old offset, old block length) of every string, by offset so shared strings are only written once
+        let mut blocks = BTreeMap::new();
+        for &i in new.keys() {
+            let Some(&Parameter::LocalPointer(off)) = self.params.get(i) else { bail!("parameter {i} is not a string") };
+            let (_, len) = string_at(&self.data, off)?;
+            blocks.insert(off, (len, i));
+        }
+        for &param in self.params.iter() {
+            if let Parameter::LocalPointer(off) = param {
+                if let btree_map::Entry::Vacant(e) = blocks.entry(off) {
+                    if let Ok((_, len)) = string_at(&self.data, off) {
+                        e.insert((len, usize::MAX));
+                    }
+                }
+            }
+        }
+
+        let mut out = BytesMut::new();
+        let mut moved = Vec::new(); // (old start, old end, new start)
+        let mut cursor = 0;
+        for (&off, &(len, i)) in blocks.iter() {
+            let off_usize = usize::try_from(off)?;
+            ensure!(off_usize >= cursor, "strings at data+{off} overlap");
+            moved.push((cursor, off_usize, out.len()));
+            out.put_slice(&self.data[cursor..off_usize]);
+            let new_off = out.len();
+            match new.get(&i) {
+                Some(block) => out.put_slice(block),
+                None => out.put_slice(&self.data[off_usize..off_usize+len])
+            }
+            moved.push((off_usize, off_usize, new_off));
+            cursor = off_usize + len;
+        }
+        moved.push((cursor, self.data.len(), out.len()));
+        out.put_slice(&self.data[cursor..]);
+
+        let remap = |off: u32| -> anyhow::Result<u32> {
+            let off = usize::try_from(off)?;
+            let &(start, _, new_start) = moved.iter()
+                .find(|&&(start, end, _)| off == start || (off > start && off < end))
+                .context("offset points into a replaced string")?;
+            Ok((new_start + off - start).try_into()?)
+        };
+        for param in self.params.iter_mut() {
+            if let Parameter::LocalPointer(off) = param {
+                *off = remap(*off)?;
+            }
+        }
+        for (off, _) in self.relocs.iter_mut() {
+            *off = remap(*off)?;
+        }
+        self.data = out.freeze();
+
+        Ok(())
+    }
+
+    /// Points relocations and jumps to the original actions in `moved` at the ones they moved to.
+    pub fn retarget(&mut self, moved: &HashMap<u32, u32>) {
+        for (_, target) in self.relocs.iter_mut() {
+            *target = moved.get(target).copied().unwrap_or(*target);
+        }
+        for param in self.params.iter_mut() {
+            if let Parameter::GlobalPointer(target) = param {
+                *target = moved.get(target).copied().unwrap_or(*target);
+            }
+        }
+    }
+
     fn to_bytes(&self, addr: Address, resolvers: &mut Vec<Resolver>, out: &mut BytesMut) -> anyhow::Result<()> {
         let new_addr = out.len();
         let canary = rand::random();
@@ -173,8 +275,22 @@ impl Action {
                 out.put_u32_le(x);
             }
         }
+        let data_pos = out.len();
         out.put_slice(&self.data);
 
+        for &(offset, target) in self.relocs.iter() {
+            let pos = data_pos + usize::try_from(offset)?;
+            ensure!(pos + 4 <= out.len(), "relocation out of bounds");
+            let canary = rand::random();
+            out[pos..pos+4].copy_from_slice(&u32::to_le_bytes(canary));
+            resolvers.push(Box::new(move |refs, buf| {
+                assert_eq!(canary, u32::from_le_bytes(buf[pos..pos+4].try_into().unwrap()));
+                let Some(&dest) = refs.get(&Reference::Action(Address { orig: target, sub: 0 })) else { return false };
+                buf[pos..pos+4].copy_from_slice(&dest.to_le_bytes());
+                true
+            }));
+        }
+
         Ok(())
     }
 }
@@ -239,7 +355,7 @@ pub fn from_bytes(mut file: Bytes) -> anyhow::Result<Stcm2> {
         let ndata = length - 16 - 12*nparams;
         let data = file.split_to(ndata.try_into()?);
 
-        let res = actions.insert(Address { orig: addr, sub: 0 }, Action { export: None, call, opcode, params, data });
+        let res = actions.insert(Address { orig: addr, sub: 0 }, Action { export: None, call, opcode, params, data, relocs: Vec::new() });
         ensure!(res.is_none());
     }
 
@@ -262,6 +378,58 @@ pub fn from_bytes(mut file: Bytes) -> anyhow::Result<Stcm2> {
     })
 }
 
+impl Stcm2 {
+    /// The string block at a file address in the global data, as some lines refer to their text
+    /// by an address (like 212) instead of a pointer to their own data.
+    pub fn global_string(&self, value: u32) -> Option<Bytes> {
+        let offset = usize::try_from(value).ok()?.checked_sub(GLOBAL_DATA_OFFSET)?;
+        if offset >= self.global_data.len() { return None }
+        let (_, len) = string_at(&self.global_data, offset.try_into().ok()?).ok()?;
+        Some(self.global_data.slice(offset..offset+len))
+    }
+
+    /// Copies the text of lines that show a string from the global data into the lines themselves,
+    /// so that they are parsed and patched like any other line. Returns how many there were.
+    pub fn inline_global_lines(&mut self) -> usize {
+        let mut n = 0;
+        let global = self.actions.iter()
+            .filter_map(|(&addr, act)| match act.params[..] {
+                [Parameter::Value(value)] if !act.call && act.opcode == Action::OP_LINE => Some((addr, self.global_string(value)?)),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+        for (addr, block) in global {
+            let act = self.actions.get_mut(&addr).unwrap();
+            act.params = vec![Parameter::LocalPointer(0)];
+            act.data = block;
+            n += 1;
+        }
+        n
+    }
+
+    /// Marks the data fields listed in `catalog` as pointers to other actions so that
+    /// `to_bytes` relocates them. The catalog is shared by all scripts, so a field that holds
+    /// 0 or a constant in this one is left as it is.
+    pub fn mark_relocations(&mut self, catalog: &[(u32, u32)]) -> anyhow::Result<()> {
+        if catalog.is_empty() { return Ok(()) }
+
+        let starts = self.actions.keys().map(|addr| addr.orig).collect::<HashSet<_>>();
+        for act in self.actions.values_mut() {
+            if act.call { continue }
+            for &(opcode, offset) in catalog.iter() {
+                if act.opcode != opcode { continue }
+                let offset_usize = usize::try_from(offset)?;
+                let Some(field) = act.data.get(offset_usize..offset_usize+4) else { continue };
+                let target = u32::from_le_bytes(field.try_into()?);
+                if !starts.contains(&target) { continue }
+                act.relocs.push((offset, target));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 type Resolver = Box<dyn Fn(&mut HashMap<Reference, u32>, &mut [u8]) -> bool>;
 
 pub fn to_bytes(input: Stcm2) -> anyhow::Result<BytesMut> {
@@ -271,7 +439,7 @@ pub fn to_bytes(input: Stcm2) -> anyhow::Result<BytesMut> {
     let mut refs = HashMap::<Reference, u32>::default();
     let export_addr_loc = output.len();
     output.put_u32_le(0);
-    output.put_u32_le(input.actions.iter().filter_map(|(_, act)| act.export.as_ref()).count().try_into()?);
+    output.put_u32_le(input.actions.values().filter_map(|act| act.export.as_ref()).count().try_into()?);
 
     for _ in 0..10 {
         output.put_u32_le(0);
@@ -310,3 +478,177 @@ pub fn to_bytes(input: Stcm2) -> anyhow::Result<BytesMut> {
 
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(opcode: u32, data: &[u32]) -> Action {
+        Action { opcode, data: data.iter().flat_map(|x| x.to_le_bytes()).collect(), ..Default::default() }
+    }
+
+    fn script(actions: impl IntoIterator<Item = (u32, Action)>) -> Stcm2 {
+        Stcm2 {
+            tag: Bytes::from(vec![0; STCM2_TAG_LENGTH]),
+            global_data: Bytes::new(),
+            actions: actions.into_iter().map(|(orig, act)| (Address { orig, sub: 0 }, act)).collect()
+        }
+    }
+
+    fn field(act: &Action, offset: usize) -> u32 {
+        u32::from_le_bytes(act.data[offset..offset+4].try_into().unwrap())
+    }
+
+    fn string(text: &[u8], qlen: u32) -> Vec<u8> {
+        let mut block = [0, qlen, 1, 4*qlen].iter().flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+        block.extend(text);
+        block.resize(16 + 4*qlen as usize, 0);
+        block
+    }
+
+    /// A field, strings "ab" and "cdefg", and another field, with the strings, the second field and
+    /// a value as parameters.
+    fn action_with_strings() -> Action {
+        let mut data = 0xaaaau32.to_le_bytes().to_vec();
+        data.extend(string(b"ab", 1));
+        data.extend(string(b"cdefg", 2));
+        data.extend(0xbbbbu32.to_le_bytes());
+        Action {
+            opcode: Action::OP_LINE,
+            params: vec![Parameter::LocalPointer(4), Parameter::Value(7), Parameter::LocalPointer(24), Parameter::LocalPointer(48)],
+            data: data.into(),
+            relocs: vec![(0, 0x100), (48, 0x200)],
+            ..Default::default()
+        }
+    }
+
+    fn strings(act: &Action) -> Vec<Bytes> {
+        [0, 2].map(|i| match act.params[i] {
+            Parameter::LocalPointer(off) => string_at(&act.data, off).unwrap().0,
+            _ => panic!("parameter {i} is not a string")
+        }).to_vec()
+    }
+
+    fn tail(act: &Action) -> u32 {
+        let Parameter::LocalPointer(off) = act.params[3] else { panic!("parameter 3 moved") };
+        assert_eq!(act.relocs, [(0, 0x100), (off, 0x200)]);
+        field(act, off as usize)
+    }
+
+    #[test]
+    fn replace_strings_grows() {
+        let mut act = action_with_strings();
+        act.replace_strings(&BTreeMap::from([(0, string(b"abcdefghi", 3).into())])).unwrap();
+        assert_eq!(strings(&act), [&b"abcdefghi"[..], b"cdefg"]);
+        assert!(matches!(act.params[..], [_, Parameter::Value(7), Parameter::LocalPointer(32), Parameter::LocalPointer(56)]));
+        assert_eq!(tail(&act), 0xbbbb);
+        assert_eq!(field(&act, 0), 0xaaaa);
+    }
+
+    #[test]
+    fn replace_strings_shrinks() {
+        let mut act = action_with_strings();
+        act.replace_strings(&BTreeMap::from([(2, string(b"c", 1).into())])).unwrap();
+        assert_eq!(strings(&act), [&b"ab"[..], b"c"]);
+        assert!(matches!(act.params[..], [Parameter::LocalPointer(4), _, Parameter::LocalPointer(24), Parameter::LocalPointer(44)]));
+        assert_eq!(tail(&act), 0xbbbb);
+        assert_eq!(act.data.len(), 48);
+    }
+
+    #[test]
+    fn replace_strings_replaces_several() {
+        let mut act = action_with_strings();
+        act.replace_strings(&BTreeMap::from([
+            (0, string(b"", 1).into()),
+            (2, string(b"cdefghijklmn", 4).into())
+        ])).unwrap();
+        assert_eq!(strings(&act), [&b""[..], b"cdefghijklmn"]);
+        assert!(matches!(act.params[..], [Parameter::LocalPointer(4), _, Parameter::LocalPointer(24), Parameter::LocalPointer(56)]));
+        assert_eq!(tail(&act), 0xbbbb);
+    }
+
+    #[test]
+    fn replace_strings_rejects_values() {
+        let mut act = action_with_strings();
+        let err = act.replace_strings(&BTreeMap::from([(1, string(b"x", 1).into())])).unwrap_err();
+        assert!(err.to_string().contains("not a string"), "{err}");
+    }
+
+    #[test]
+    fn inline_global_lines_copies_their_strings() {
+        let value = |v: u32| Action { opcode: Action::OP_LINE, params: vec![Parameter::Value(v)], ..Default::default() };
+        let at = GLOBAL_DATA_OFFSET as u32 + 16;
+        let mut stcm2 = script([
+            (0x100, value(at)),
+            (0x200, value(3)),
+            (0x300, Action { opcode: 0x10, ..value(at) }),
+            (0x400, value(at + 1000)),
+            (0x500, value(GLOBAL_DATA_OFFSET as u32))
+        ]);
+        let mut global_data = vec![0xff; 16];
+        global_data.extend(string(b"abc", 1));
+        stcm2.global_data = global_data.into();
+
+        assert_eq!(stcm2.inline_global_lines(), 1);
+        let params = stcm2.actions.values().map(|act| act.params.clone()).collect::<Vec<_>>();
+        assert!(matches!(params[0][..], [Parameter::LocalPointer(0)]));
+        assert!(params[1..].iter().all(|p| matches!(p[..], [Parameter::Value(_)])));
+        let line = stcm2.actions.values().next().unwrap();
+        assert_eq!(decode_string(0, line.data.clone()).unwrap(), &b"abc"[..]);
+    }
+
+    #[test]
+    fn mark_relocations_skips_fields_that_are_not_actions() {
+        let mut stcm2 = script([
+            (0x100, action(1, &[0x200, 0x300])),
+            (0x200, action(1, &[0, 0x100])),
+            (0x300, action(1, &[7]))
+        ]);
+        stcm2.mark_relocations(&[(1, 0)]).unwrap();
+        let relocs = stcm2.actions.values().map(|act| act.relocs.clone()).collect::<Vec<_>>();
+        assert_eq!(relocs, [vec![(0, 0x200)], vec![], vec![]]);
+    }
+
+    #[test]
+    fn to_bytes_relocates_marked_fields() {
+        let mut stcm2 = script([
+            (0x100, action(1, &[0x300, 5])),
+            (0x200, action(2, &[])),
+            (0x300, action(3, &[]))
+        ]);
+        stcm2.mark_relocations(&[(1, 0), (1, 4)]).unwrap();
+        // a new action moves the target
+        stcm2.actions.insert(Address { orig: 0x200, sub: 1 }, action(2, &[1, 2, 3]));
+
+        let patched = from_bytes(to_bytes(stcm2).unwrap().freeze()).unwrap();
+        let addrs = patched.actions.keys().map(|addr| addr.orig).collect::<Vec<_>>();
+        let first = &patched.actions.values().next().unwrap();
+        assert_eq!(field(first, 0), addrs[3]);
+        assert_eq!(field(first, 4), 5);
+    }
+
+    #[test]
+    fn to_bytes_resolves_every_kind_of_pointer() {
+        let mut caller = action(0x200, &[0x300, 0]);
+        caller.call = true;
+        caller.params = vec![Parameter::GlobalPointer(0x300), Parameter::LocalPointer(4)];
+        caller.relocs = vec![(0, 0x300)];
+        let stcm2 = script([(0x100, caller), (0x200, action(1, &[])), (0x300, action(2, &[]))]);
+
+        let patched = from_bytes(to_bytes(stcm2).unwrap().freeze()).unwrap();
+        let addrs = patched.actions.keys().map(|addr| addr.orig).collect::<Vec<_>>();
+        let caller = patched.actions.values().next().unwrap();
+        // every canary was replaced by its address
+        assert_eq!(caller.opcode, addrs[1]);
+        assert!(matches!(caller.params[..], [Parameter::GlobalPointer(a), Parameter::LocalPointer(4)] if a == addrs[2]));
+        assert_eq!(field(caller, 0), addrs[2]);
+    }
+
+    #[test]
+    fn to_bytes_fails_on_relocation_to_missing_action() {
+        let mut act = action(1, &[0x999]);
+        act.relocs = vec![(0, 0x999)];
+        let err = to_bytes(script([(0x100, act)])).unwrap_err();
+        assert!(err.to_string().contains("made no progress"), "{err}");
+    }
+}
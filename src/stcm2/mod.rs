@@ -1,22 +1,112 @@
-mod format;
 mod parse;
 mod analyze;
+mod diff;
 mod patch;
+mod pointers;
+mod report;
+mod scenes;
+mod strings;
+mod text;
+mod verify;
 
+use std::{ops::RangeInclusive, str::FromStr};
+
+use anyhow::{bail, ensure, Context as _};
+use bytes::Bytes;
 use rusqlite::Connection;
 use clap::{Parser, ValueEnum};
+use blume::stcm2::format;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, ValueEnum)]
 enum Mode {
     Analyze,
-    Patch
+    Reanalyze,
+    Patch,
+    Pointers,
+    Extract,
+    Scenes,
+    Diff
+}
+
+#[derive(Clone, Debug)]
+enum Selection {
+    All,
+    // all scripts that contain dialogue
+    Auto,
+    Ranges(Vec<RangeInclusive<u32>>)
+}
+
+impl FromStr for Selection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "auto" => Ok(Self::Auto),
+            s => s.split(',').map(|r| match r.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (start.trim().parse()?, end.trim().parse()?);
+                    ensure!(start <= end, "range {r} is reversed");
+                    Ok(start..=end)
+                },
+                None => {
+                    let id = r.trim().parse()?;
+                    Ok(id..=id)
+                }
+            }).collect::<anyhow::Result<_>>().map(Self::Ranges).with_context(|| format!("bad selection: {s}"))
+        }
+    }
+}
+
+impl Selection {
+    fn resolve(&self, db: &Connection) -> anyhow::Result<Vec<u32>> {
+        let mut stmt = db.prepare("SELECT id FROM scripts ORDER BY id")?;
+        let ids = stmt.query_map((), |row| row.get(0))?.collect::<Result<Vec<u32>, _>>()?;
+        let ids = match self {
+            Self::All | Self::Auto => ids,
+            Self::Ranges(ranges) => ids.into_iter().filter(|id| ranges.iter().any(|r| r.contains(id))).collect()
+        };
+        ensure!(!ids.is_empty(), "no scripts selected");
+        Ok(ids)
+    }
+
+    fn single(&self) -> anyhow::Result<u32> {
+        match self {
+            Self::Ranges(ranges) if ranges.len() == 1 && ranges[0].start() == ranges[0].end() => Ok(*ranges[0].start()),
+            _ => bail!("this mode takes exactly one script id")
+        }
+    }
+}
+
+/// Loads the selected scripts from the database.
+fn load(db: &Connection, ids: &Selection) -> anyhow::Result<Vec<(u32, Bytes)>> {
+    let mut stmt = db.prepare("SELECT script FROM scripts WHERE id = ?")?;
+    ids.resolve(db)?.into_iter()
+        .map(|id| Ok((id, stmt.query_row((id,), |row| Ok(Bytes::copy_from_slice(row.get_ref(0)?.as_blob()?)))?)))
+        .collect()
 }
 
 #[derive(Parser)]
 pub struct Args {
     mode: Mode,
-    #[arg(help = "id of scripts to analyze")]
-    id: u32,
+    #[arg(help = "ids of scripts: comma-separated ids and ranges (100-199), all, or auto (scripts with dialogue; for patch, both mean scripts with translations)")]
+    ids: Selection,
+    #[arg(long, help = "register the consistent fields found by pointers or scenes")]
+    mark: bool,
+    #[arg(long, help = "apply the changes found by reanalyze or scenes")]
+    update: bool,
+    #[arg(long, value_delimiter = ',', help = "register string params that display text for extract, as OPCODE.PARAM or call:OPCODE.PARAM in hex")]
+    text: Vec<strings::StringParam>,
+    #[arg(long, value_delimiter = ',', default_value = "vntl-greedy-20240823", help = "translation sessions for patch, in order of preference")]
+    sessions: Vec<String>,
+    #[arg(long, value_enum, default_value_t = report::Format::Table, help = "how patch reports problems")]
+    report: report::Format,
+    #[arg(long, help = "only count the entries from each session in the table report instead of listing the session of every entry")]
+    no_sources: bool,
+    #[arg(long, help = "patch entries that have problems with the original japanese instead of failing")]
+    fallback: bool,
+    #[arg(long, help = "when patching several scripts, keep the ones that succeeded if others fail")]
+    keep_going: bool,
     #[arg(from_global)]
     dry_run: bool
 }
@@ -24,6 +114,52 @@ pub struct Args {
 pub fn run(db: Connection, args: Args) -> anyhow::Result<()> {
     match args.mode {
         Mode::Analyze => analyze::analyze(db, args),
-        Mode::Patch => patch::patch(db, args)
+        Mode::Reanalyze => analyze::reanalyze(db, args),
+        Mode::Patch => patch::patch(db, args),
+        Mode::Pointers => pointers::pointers(db, args),
+        Mode::Extract => strings::extract(db, args),
+        Mode::Scenes => scenes::scenes(db, args),
+        Mode::Diff => diff::diff(db, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(selection: &str) -> anyhow::Result<Vec<u32>> {
+        let db = Connection::open_in_memory()?;
+        db.execute_batch("
+            CREATE TABLE scripts(id INTEGER PRIMARY KEY, script BLOB NOT NULL) STRICT;
+            INSERT INTO scripts(id, script) VALUES (99, x''), (100, x''), (120, x''), (150, x''), (199, x''), (200, x'');
+        ")?;
+        selection.parse::<Selection>()?.resolve(&db)
+    }
+
+    #[test]
+    fn selection_of_ids_and_ranges() {
+        assert_eq!(ids("100").unwrap(), [100]);
+        assert_eq!(ids("99, 150-200").unwrap(), [99, 150, 199, 200]);
+        assert_eq!(ids("all").unwrap().len(), 6);
+    }
+
+    #[test]
+    fn overlapping_ranges_select_once() {
+        assert_eq!(ids("100-150,120-199,150").unwrap(), [100, 120, 150, 199]);
+    }
+
+    #[test]
+    fn reversed_range_is_an_error() {
+        let err = "199-100".parse::<Selection>().unwrap_err();
+        assert!(format!("{err:#}").contains("reversed"), "{err:#}");
+    }
+
+    #[test]
+    fn junk_is_an_error() {
+        for junk in ["", "abc", "100-", "-100", "1-2-3", "100,,101", "auto,100"] {
+            assert!(junk.parse::<Selection>().is_err(), "{junk:?} parsed");
+        }
+        // parses, but matches nothing
+        assert!(ids("300").is_err());
     }
 }
@@ -1,21 +1,41 @@
 use std::mem;
 
-use super::format::Operation;
-use anyhow::ensure;
-use encoding_rs::SHIFT_JIS;
+use crate::{font::Widths, sjis};
+
+use super::{format::{Action, Operation}, text};
+use anyhow::{ensure, Context as _};
+
+#[derive(Clone, Debug)]
+pub struct ChoiceOption {
+    pub addr: u32,
+    pub id: u32,
+    pub text: String
+}
+
+// how the original text was laid out on screen
+#[derive(Clone, Debug, Default)]
+pub struct Layout {
+    // width of each OP_LINE, in the units of the font's width model
+    pub widths: Vec<usize>,
+    // whether the entry ends the page
+    pub pagebreak: bool
+}
 
 #[derive(Clone, Debug)]
-#[allow(dead_code)]
 pub enum Dialogue {
     Choice {
         addr: u32,
+        speaker: String,
         prompt: String,
-        options: Vec<String>
+        options: Vec<ChoiceOption>,
+        layout: Layout
     },
     Line {
         addr: u32,
         speaker: String,
-        line: String
+        line: String,
+        voice: Option<u32>,
+        layout: Layout
     }
 }
 
@@ -23,21 +43,39 @@ pub enum Dialogue {
 struct ParseState {
     addr: Option<u32>,
     speaker: String,
-    options: Vec<String>,
-    line: String
+    options: Vec<ChoiceOption>,
+    line: String,
+    voice: Option<u32>,
+    widths: Vec<usize>
+}
+
+impl ParseState {
+    fn flush(&mut self, di: &mut Vec<Dialogue>, pagebreak: bool) -> anyhow::Result<()> {
+        let ParseState { addr, speaker, options, line, voice, widths } = mem::take(self);
+        let layout = Layout { widths, pagebreak };
+        if !options.is_empty() {
+            ensure!(addr.is_some());
+            di.push(Dialogue::Choice { addr: addr.unwrap(), speaker, prompt: line, options, layout })
+        } else if line.is_empty() {
+            // nothing to attach the voice to yet, so it waits for the line after other actions
+            self.voice = voice;
+        } else {
+            ensure!(addr.is_some() && options.is_empty());
+            di.push(Dialogue::Line { addr: addr.unwrap(), speaker, line, voice, layout })
+        }
+        Ok(())
+    }
 }
 
 fn trim(s: &str) -> &str {
     s.trim_matches(|c: char| c.is_whitespace() || c == '・')
 }
 
-fn decode(b: &[u8]) -> String {
-    let (dec, rep) = SHIFT_JIS.decode_without_bom_handling(b);
-    assert!(!rep, "replacements were made in {dec}");
-    dec.into_owned()
+fn decode(b: &[u8]) -> anyhow::Result<String> {
+    sjis::decode(b).with_context(|| format!("could not decode {}", sjis::decode_lossy(b)))
 }
 
-pub fn parse(it: impl IntoIterator<Item = Operation>) -> anyhow::Result<Vec<Dialogue>> {
+pub fn parse(it: impl IntoIterator<Item = Operation>, widths: &Widths) -> anyhow::Result<Vec<Dialogue>> {
     use Operation::*;
 
     let mut st = ParseState::default();
@@ -47,28 +85,27 @@ pub fn parse(it: impl IntoIterator<Item = Operation>) -> anyhow::Result<Vec<Dial
             Line { addr, s } => {
                 ensure!(st.options.is_empty(), "incorrect line state\nst = {st:#X?}");
                 if st.addr.is_none() { st.addr = Some(addr); }
-                st.line.push_str(trim(&decode(&s)));
+                st.line.push_str(trim(&decode(&s)?));
+                st.widths.push(text::width(&s, widths));
             },
-            Choice { addr, id: _, s } => {
+            Choice { addr, id, s } => {
                 if st.addr.is_none() { st.addr = Some(addr); }
-                st.options.push(decode(&s));
+                st.options.push(ChoiceOption { addr, id, text: decode(&s)? });
             },
             Speaker { addr, s } => {
                 ensure!(st.speaker.is_empty() && st.options.is_empty(), "incorrect speaker state\nst = {st:#X?}");
                 if st.addr.is_none() { st.addr = Some(addr); }
-                st.speaker.push_str(&decode(&s));
+                st.speaker.push_str(&decode(&s)?);
             },
-            _ => {
-                let ParseState { addr, speaker, options, line } = mem::take(&mut st);
-                if line.is_empty() {}
-                else if !options.is_empty() {
-                    ensure!(addr.is_some());
-                    di.push(Dialogue::Choice { addr: addr.unwrap(), prompt: line, options })
-                } else {
-                    ensure!(addr.is_some() && options.is_empty());
-                    di.push(Dialogue::Line { addr: addr.unwrap(), speaker, line })
+            Voice { addr: _, id } => {
+                // the voice comes before the speaker and line it belongs to
+                if !st.line.is_empty() || !st.options.is_empty() {
+                    st.flush(&mut di, false)?;
                 }
-            }
+                st.voice = Some(id);
+            },
+            Unknown(Action { call: false, opcode: Action::OP_YIELD, .. }) => st.flush(&mut di, true)?,
+            _ => st.flush(&mut di, false)?
         }
     }
 
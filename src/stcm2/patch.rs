@@ -1,15 +1,12 @@
-use std::{collections::{BTreeMap, HashMap}, iter, mem, slice};
+use std::{collections::{BTreeMap, HashMap, HashSet}, mem, panic::{self, AssertUnwindSafe}};
 
-use anyhow::{bail, ensure};
+use anyhow::{anyhow, bail, ensure, Context as _};
 use bytes::{BufMut as _, Bytes, BytesMut};
-use encoding_rs::SHIFT_JIS;
-use once_cell::sync::Lazy;
-use rusqlite::Connection;
-use crate::stcm2::format::Address;
+use rayon::iter::{IntoParallelIterator as _, ParallelIterator as _};
+use rusqlite::{params_from_iter, Connection, DropBehavior};
+use crate::{characters::Registry, font::{Widths, MAX_LINE_CELLS}, glyphs::Glyphs, markup::{self, Markup}, sjis, stcm2::format::Address};
 
-use super::{format::{self, Action, Parameter, Stcm2}, Args};
-
-const MAX_LINE_LENGTH: usize = 45; // game will print a debug message if the line is over 45 halfwidth chars
+use super::{format::{self, Action, Parameter, Stcm2}, pointers, report::Report, strings, text::{tokenize, Token}, verify, Args, Selection};
 
 fn encode_string(enc: &[u8]) -> anyhow::Result<BytesMut> {
     let qlen = enc.len().div_ceil(4);
@@ -24,87 +21,18 @@ fn encode_string(enc: &[u8]) -> anyhow::Result<BytesMut> {
     Ok(b)
 }
 
-// trust me on this
-static SPEAKERS: Lazy<HashMap<&[u8], Bytes>> = Lazy::new(|| HashMap::from([
-    (&b"#Name[1]"[..], encode_string(b"#Name[1]").unwrap().freeze()),
-    (b"\x81H\x81H\x81H", encode_string(b"\x81H\x81H\x81H").unwrap().freeze()), // ???
-    (b"\x83_\x83j\x83G\x83\x89", encode_string(b"Daniela").unwrap().freeze()),
-    (b"\x83\x94\x83B\x83N\x83g\x83\x8b", encode_string(b"Victor").unwrap().freeze()),
-    (b"\x83I\x81[\x83M\x83\x85\x83X\x83g", encode_string(b"Auguste").unwrap().freeze()),
-    (b"\x83C\x83\x8a\x83\x84", encode_string(b"Ilya").unwrap().freeze()),
-    (b"\x83\x8a\x83`\x83\x83\x81[\x83h", encode_string(b"Richard").unwrap().freeze()),
-    (b"\x83\x84\x83R\x83u", encode_string(b"Jacob").unwrap().freeze()),
-    (b"\x83o\x81[\x83W\x83j\x83A", encode_string(b"Virginia").unwrap().freeze()),
-    (b"\x83W\x83F\x83\x89\x83\x8b\x83h", encode_string(b"Gerald").unwrap().freeze()),
-    (b"\x83R\x83\x93\x83\x89\x83b\x83h", encode_string(b"Conrad").unwrap().freeze()),
-    (b"\x83o\x83\x89\x81[\x83W\x83\x85", encode_string(b"Balazs").unwrap().freeze()),
-    (b"\x83N\x83\x89\x83E\x83X", encode_string(b"Claus").unwrap().freeze()),
-    (b"\x83X\x83e\x83t\x83@\x83\x93", encode_string(b"Stefan").unwrap().freeze()),
-    (b"\x83\x8c\x83\x8b\x83\x80", encode_string(b"Larm").unwrap().freeze()),
-    (b"\x83\x8c\x83I", encode_string(b"Leo").unwrap().freeze()),
-    (b"\x83M\x83\x8b\x83x\x83\x8b\x83g", encode_string(b"Gilbert").unwrap().freeze()),
-    (b"\x83G\x83~\x83\x8a\x83I", encode_string(b"Emilio").unwrap().freeze()),
-    (b"\x83f\x83B\x83\x81\x83g\x83\x8a\x83I", encode_string(b"Demetrio").unwrap().freeze()),
-    (b"\x83I\x83\x8a\x83\x94\x83B\x83A", encode_string(b"Olivia").unwrap().freeze()),
-    (b"\x83\x94\x83H\x83\x8b\x83}\x81[", encode_string(b"Volmer").unwrap().freeze()),
-    (b"\x83_\x83\x93\x83P\x83\x8b\x83n\x83C\x83g", encode_string(b"Dunkelheit").unwrap().freeze()),
-    (b"\x90l\x98T", encode_string(b"Werewolf").unwrap().freeze()),
-    (b"\x8d\x95\x88\xdf\x82\xcc\x92j\x90\xab", encode_string(b"Man in black").unwrap().freeze()),
-    (b"\x83N\x83\x89\x83E\x83X\x82\xcc\x90\xba", encode_string(b"Claus's voice").unwrap().freeze()),
-    (b"\x83o\x81[\x83W\x83j\x83A\x82\xcc\x90\xba", encode_string(b"Virginia's voice").unwrap().freeze()),
-    (b"\x83I\x81[\x83M\x83\x85\x83X\x83g\x82\xcc\x90\xba", encode_string(b"Auguste's voice").unwrap().freeze()),
-    (b"\x83X\x83e\x83t\x83@\x83\x93\x82\xcc\x90\xba", encode_string(b"Stefan's voice").unwrap().freeze())
-]));
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Token {
-    Fullwidth([u8; 2]),
-    Halfwidth(u8),
-    Name
-}
-
-impl Token {
-    fn width(self) -> usize {
-        match self {
-            Self::Fullwidth(_) => 2,
-            Self::Halfwidth(_) => 1,
-            Self::Name => 10
-        }
-    }
-
-    fn rep(&self) -> &[u8] {
-        match self {
-            Self::Fullwidth(c) => c,
-            Self::Halfwidth(c) => slice::from_ref(c),
-            Self::Name => b"#Name[1]"
-        }
-    }
+fn encode_translation(translation: &str, glyphs: &Glyphs, markup: &Markup) -> anyhow::Result<Vec<u8>> {
+    glyphs.encode(&markup.restore(translation))
 }
 
-fn tokenize(mut input: &[u8]) -> impl Iterator<Item = Token> + '_ {
-    iter::from_fn(move || {
-        if input.is_empty() {
-            None
-        } else if let Some(sl) = input.strip_prefix(b"#Name[1]") {
-            input = sl;
-            Some(Token::Name)
-        } else if matches!(input[0], 0x81..=0x9F | 0xE0..=0xFC) {
-            let &[ch, cl, ref sl @ ..] = input else { panic!("sjis terminates early") };
-            input = sl;
-            Some(Token::Fullwidth([ch, cl]))
-        } else {
-            let &[c, ref sl @ ..] = input else { unreachable!() };
-            input = sl;
-            Some(Token::Halfwidth(c))
-        }
-    })
-}
-
-fn split_lines_intelligent(input: &[Token]) -> Vec<Vec<u8>> {
+fn split_lines_intelligent(input: &[Token], widths: &Widths) -> anyhow::Result<Vec<Vec<u8>>> {
     const FULLWIDTH_SPACE: [u8; 2] = [0x81, 0x40];
+    let space = widths.glyph(b" ");
 
     let mut v = Vec::new();
+    // in the width model, and in halfwidth cells for the game's limit
     let mut cur_width = 0;
+    let mut cur_cells = 0;
     let mut cur_line = Vec::default();
     let mut indent = false;
     for atom in input.split(|&t| t == Token::Halfwidth(b' ')) {
@@ -112,28 +40,37 @@ fn split_lines_intelligent(input: &[Token]) -> Vec<Vec<u8>> {
             if cur_width == 0 {
                 if indent {
                     cur_line.extend_from_slice(&FULLWIDTH_SPACE);
-                    cur_width += 2;
+                    cur_width += widths.glyph(&FULLWIDTH_SPACE);
+                    cur_cells += 2;
                 }
                 for &tok in atom.iter() {
                     cur_line.extend_from_slice(tok.rep());
-                    cur_width += tok.width();
+                    cur_width += tok.width(widths);
+                    cur_cells += tok.cells(widths);
+                }
+                if cur_width > widths.textbox || cur_cells > MAX_LINE_CELLS {
+                    let word = atom.iter().flat_map(|tok| tok.rep()).copied().collect::<Vec<_>>();
+                    bail!("too wide for a line: {}", sjis::decode_lossy(&word));
                 }
-                if matches!(atom[0], Token::Fullwidth(_)) {
+                if matches!(atom.first(), Some(Token::Fullwidth(_))) {
                     indent = true;
                 }
             } else {
-                let width = atom.iter().map(|&tok| tok.width()).sum::<usize>();
-                if cur_width + width + 1 > MAX_LINE_LENGTH {
-                    assert!(cur_width <= MAX_LINE_LENGTH);
+                let width = atom.iter().map(|&tok| tok.width(widths)).sum::<usize>();
+                let cells = atom.iter().map(|&tok| tok.cells(widths)).sum::<usize>();
+                if cur_width + space + width > widths.textbox || cur_cells + 1 + cells > MAX_LINE_CELLS {
                     v.push(mem::take(&mut cur_line));
                     cur_width = 0;
+                    cur_cells = 0;
                     continue;
                 }
                 cur_line.push(b' ');
-                cur_width += 1;
+                cur_width += space;
+                cur_cells += 1;
                 for &tok in atom.iter() {
                     cur_line.extend_from_slice(tok.rep());
-                    cur_width += tok.width();
+                    cur_width += tok.width(widths);
+                    cur_cells += tok.cells(widths);
                 }
             }
 
@@ -141,41 +78,217 @@ fn split_lines_intelligent(input: &[Token]) -> Vec<Vec<u8>> {
         }
     }
     if cur_width > 0 {
-        assert!(cur_width <= MAX_LINE_LENGTH, "{}", SHIFT_JIS.decode_without_bom_handling(&cur_line).0);
         v.push(cur_line);
     }
-    v
+    Ok(v)
 }
 
-pub fn patch(mut db: Connection, args: Args) -> anyhow::Result<()> {
-    let tx = db.transaction()?;
+/// Checks that text the game doesn't wrap, like a choice, fits on one line.
+fn fit_line(enc: &[u8], widths: &Widths) -> anyhow::Result<()> {
+    let width = tokenize(enc).map(|tok| tok.width(widths)).sum::<usize>();
+    let cells = tokenize(enc).map(|tok| tok.cells(widths)).sum::<usize>();
+    ensure!(width <= widths.textbox && cells <= MAX_LINE_CELLS, "too wide for a line: {width} of {} wide, {cells} of {MAX_LINE_CELLS} cells", widths.textbox);
+    Ok(())
+}
+
+const LINES_PER_PAGE: usize = 3;
+
+fn ends_sentence(line: &[u8]) -> bool {
+    let line = sjis::decode_lossy(line);
+    let line = line.trim_end().trim_end_matches(['"', '\'', ')', '」', '』', '）']);
+    line.ends_with(['.', '!', '?', '。', '！', '？', '…'])
+}
+
+/// Splits wrapped lines into the fewest pages, as evenly as possible and preferably between sentences.
+/// Returns the number of lines on each page, which is a single empty page for no lines.
+fn paginate(lines: &[Vec<u8>]) -> Vec<usize> {
+    const MID_SENTENCE_COST: usize = 2;
+
+    let n = lines.len();
+    if n == 0 {
+        return vec![0];
+    }
+    let npages = n.div_ceil(LINES_PER_PAGE).max(1);
+    // best[p][i]: (cost, size of the last page) of putting the first i lines on p pages
+    let mut best = vec![vec![None::<(usize, usize)>; n+1]; npages+1];
+    best[0][0] = Some((0, 0));
+    for p in 1..=npages {
+        for i in 1..=n {
+            for size in 1..=LINES_PER_PAGE.min(i) {
+                let Some((cost, _)) = best[p-1][i-size] else { continue };
+                let mut cost = cost + (LINES_PER_PAGE - size).pow(2);
+                if i < n && !ends_sentence(&lines[i-1]) {
+                    cost += MID_SENTENCE_COST;
+                }
+                if best[p][i].is_none_or(|(c, _)| cost < c) {
+                    best[p][i] = Some((cost, size));
+                }
+            }
+        }
+    }
+
+    let mut sizes = Vec::new();
+    let (mut p, mut i) = (npages, n);
+    while let Some((_, size)) = best[p][i].filter(|_| i > 0) {
+        sizes.push(size);
+        p -= 1;
+        i -= size;
+    }
+    sizes.reverse();
+    sizes
+}
+
+/// What patching a script changed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    pub lines: usize,
+    pub pages: usize,
+    pub bytes: isize
+}
+
+// translations of one script by address, each from the best session that has one
+#[derive(Clone, Debug, Default)]
+struct Translations {
+    lines: HashMap<u32, String>,
+    choices: HashMap<u32, String>,
+    strings: HashMap<u32, BTreeMap<usize, String>>,
+    // number of lines of the original text
+    orig_lines: HashMap<u32, usize>,
+    nosplit: HashSet<u32>
+}
+
+// what patching any script depends on
+struct Env {
+    characters: Registry,
+    widths: Widths,
+    glyphs: Glyphs,
+    relocations: Vec<(u32, u32)>,
+    fallback: bool
+}
+
+fn load_translations(tx: &Connection, id: u32, sessions: &[String], markup: &Markup, report: &mut Report) -> anyhow::Result<Translations> {
+    // translations come from the first session in the list that has one
+    let rank = |session: &str| sessions.iter().position(|s| s == session);
+    // by (address, param), where only strings have a param
+    let mut sources = BTreeMap::<(u32, Option<usize>), usize>::new();
 
     let mut tls = HashMap::new();
     {
-        let mut stmt = tx.prepare("SELECT address, translation FROM translations WHERE session = 'vntl-greedy-20240823' AND scriptid = ?")?;
-        let mut rows = stmt.query((args.id,))?;
+        let mut stmt = tx.prepare_cached("
+            SELECT session, address, translation, line FROM translations JOIN lines USING (scriptid, address)
+            WHERE scriptid = ?
+        ")?;
+        let mut rows = stmt.query((id,))?;
+        while let Some(row) = rows.next()? {
+            let (session, address, translation, line) = <(String, u32, String, String)>::try_from(row)?;
+            let Some(rank) = rank(&session) else { continue };
+            if sources.get(&(address, None)).is_some_and(|&r| r <= rank) { continue }
+            sources.insert((address, None), rank);
+            tls.insert(address, (translation, line));
+        }
+    }
+    for (&address, (translation, line)) in tls.iter() {
+        if let Err(e) = markup::validate(line, &markup.restore(translation)) {
+            report.warning(id, address, "markup", e, translation);
+        }
+    }
+    let lines = tls.into_iter().map(|(address, (translation, _))| (address, translation)).collect::<HashMap<_, _>>();
+
+    let mut orig_lines = HashMap::new();
+    {
+        let mut stmt = tx.prepare_cached("SELECT address, widths FROM layouts WHERE scriptid = ?")?;
+        let mut rows = stmt.query((id,))?;
+        while let Some(row) = rows.next()? {
+            let (address, widths) = <(u32, String)>::try_from(row)?;
+            orig_lines.insert(address, widths.split_whitespace().count());
+        }
+    }
+
+    let mut ctls = HashMap::new();
+    {
+        let mut stmt = tx.prepare_cached("SELECT session, address, translation FROM choicetranslations WHERE scriptid = ?")?;
+        let mut rows = stmt.query((id,))?;
         while let Some(row) = rows.next()? {
-            let (address, translation) = <(u32, String)>::try_from(row)?;
-            tls.insert(address, translation);
+            let (session, address, translation) = <(String, u32, String)>::try_from(row)?;
+            let Some(rank) = rank(&session) else { continue };
+            if ctls.get(&address).is_some_and(|&(r, _)| r <= rank) { continue }
+            ctls.insert(address, (rank, translation));
         }
     }
+    for (&address, &(rank, _)) in ctls.iter() {
+        sources.insert((address, None), rank);
+    }
+    let choices = ctls.into_iter().map(|(address, (_, translation))| (address, translation)).collect::<HashMap<_, _>>();
 
-    let file = tx.query_row("SELECT script FROM scripts WHERE id = ?", (args.id,), |row| Ok(Bytes::copy_from_slice(row.get_ref(0)?.as_blob()?)))?;
+    let mut stls = HashMap::<u32, BTreeMap<usize, (usize, String)>>::new();
+    {
+        let mut stmt = tx.prepare_cached(&format!("
+            SELECT session, address, param, translation FROM stringtranslations JOIN strings USING (scriptid, address, param)
+            WHERE scriptid = ? AND {}
+        ", strings::REGISTERED))?;
+        let mut rows = stmt.query((id,))?;
+        while let Some(row) = rows.next()? {
+            let (session, address, param, translation) = <(String, u32, usize, String)>::try_from(row)?;
+            let Some(rank) = rank(&session) else { continue };
+            let strs = stls.entry(address).or_default();
+            if strs.get(&param).is_some_and(|&(r, _)| r <= rank) { continue }
+            strs.insert(param, (rank, translation));
+        }
+    }
+    let strings = stls.into_iter().map(|(address, strs)| {
+        for (&param, &(rank, _)) in strs.iter() {
+            sources.insert((address, Some(param)), rank);
+        }
+        (address, strs.into_iter().map(|(param, (_, s))| (param, s)).collect::<BTreeMap<_, _>>())
+    }).collect::<HashMap<_, _>>();
+
+    for (&(address, param), &rank) in sources.iter() {
+        report.source(id, address, param, &sessions[rank]);
+    }
 
-    let stcm2 = format::from_bytes(file)?;
+    let nosplit = tx.prepare_cached("SELECT address FROM nopagesplit WHERE scriptid = ?")?
+        .query_map((id,), |row| row.get(0))?
+        .collect::<Result<HashSet<u32>, _>>()?;
 
+    Ok(Translations { lines, choices, strings, orig_lines, nosplit })
+}
+
+fn patch_script(id: u32, file: Bytes, tls: Translations, env: &Env, report: &mut Report) -> anyhow::Result<(Bytes, Summary)> {
+    let Translations { lines: mut tls, choices: mut ctls, strings: mut stls, orig_lines, nosplit } = tls;
+    let Env { characters, widths, glyphs, .. } = env;
+    let orig_len = file.len();
+    let mut summary = Summary::default();
+
+    let mut stcm2 = format::from_bytes(file)?;
+    stcm2.mark_relocations(&env.relocations)?;
+    // these become ordinary lines, whether or not they are translated
+    stcm2.inline_global_lines();
     let mut cur_addr = None;
     let mut new_actions = BTreeMap::new();
     let mut buf_actions = BTreeMap::new();
+    // original lines that were replaced, with the entry their block starts at
+    let mut replaced = HashMap::new();
 
     for (addr, act) in stcm2.actions {
         match act {
             Action { call: false, opcode: Action::OP_SPEAKER, ref export, ref params, .. } => {
                 ensure!(cur_addr.is_none() && buf_actions.is_empty() && export.is_none() && matches!(&params[..], &[Parameter::LocalPointer(0)]));
-                let name = format::decode_string(0, act.data)?;
-                let Some(speaker) = SPEAKERS.get(&name[..]) else { bail!("could not get speaker for {:?}", name) };
+                let name = format::decode_string(0, act.data.clone())?;
+                let name = sjis::decode(&name).context("could not decode speaker")?;
+                // the player's name is a code that the game fills in
+                let speaker = if markup::code_len(name.as_bytes()) == Some(name.len()) {
+                    act.data.clone()
+                } else {
+                    match characters.decode(&name).and_then(|en| encode_translation(&en.to_string(), glyphs, &widths.markup)) {
+                        Ok(en) => encode_string(&en)?.freeze(),
+                        Err(e) => {
+                            report.error(id, addr.orig, "speaker", e, name);
+                            act.data.clone()
+                        }
+                    }
+                };
                 new_actions.insert(addr, Action {
-                    data: speaker.clone(),
+                    data: speaker,
                     ..act
                 });
                 cur_addr = Some(Address {
@@ -185,8 +298,8 @@ pub fn patch(mut db: Connection, args: Args) -> anyhow::Result<()> {
             },
             Action { call: false, opcode: Action::OP_LINE, ref export, ref params, .. } => {
                 match params[..] {
-                    [Parameter::Value(212)] => {
-                        // idk
+                    [Parameter::Value(_)] => {
+                        // not an address in the global data, so nothing known to translate
                         ensure!(cur_addr.is_none());
                         new_actions.insert(addr, act);
                         continue;
@@ -200,47 +313,43 @@ pub fn patch(mut db: Connection, args: Args) -> anyhow::Result<()> {
                     cur_addr = Some(addr);
                 }
             },
-            act => {
-                if act.opcode != Action::OP_CHOICE {
-                    if let Some(mut addr) = cur_addr {
-                        if let Some(mut translation) = tls.remove(&addr.orig) {
-                            const REPLACE: &[(&str, &str)] = &[
-                                // this should be in the db already :/
-                                ("Mary", "#Name[1]"),
-
-                                ("ä", "a"),
-
-                                // fullwidth -> halfwidth
-                                //("「", "｢"),
-                                //("」", "｣"),
-                                // ("（", "("),
-                                // ("）", ")")
-                            ];
-
-                            buf_actions.clear();
-
-                            for &(from, to) in REPLACE.iter() {
-                                translation = translation.replace(from, to);
-                            }
-
-                            let (translation, _, false) = SHIFT_JIS.encode(&translation) else { bail!("found invalid sjis chars") };
-
-                            let tokens = tokenize(&translation).collect::<Vec<_>>();
-                            let lines = split_lines_intelligent(&tokens);
-
-                            let mut yield_counter = 0;
+            mut act => {
+                if let Some(mut addr) = cur_addr {
+                    let lines = tls.remove(&addr.orig).and_then(|translation| {
+                        encode_translation(&translation, glyphs, &widths.markup)
+                            .and_then(|enc| split_lines_intelligent(&tokenize(&enc).collect::<Vec<_>>(), widths))
+                            // an empty translation would take the original text with it
+                            .and_then(|lines| if lines.is_empty() { Err(anyhow!("translation is empty")) } else { Ok(lines) })
+                            .map_err(|e| report.error(id, addr.orig, "line", e, translation))
+                            .ok()
+                    });
+                    if let Some(lines) = lines {
+                        for line in mem::take(&mut buf_actions).into_keys() {
+                            replaced.insert(line.orig, addr.orig);
+                        }
+                        summary.lines += 1;
+
+                        let nlines = lines.len();
+                        let pages = if nosplit.contains(&addr.orig) { vec![nlines] } else { paginate(&lines) };
+                        if pages.len() > 1 || nlines > LINES_PER_PAGE {
+                            let orig = orig_lines.get(&addr.orig).map_or_else(String::new, |orig| format!(", originally {orig} lines"));
+                            let split = if nosplit.contains(&addr.orig) { ", not split" } else { "" };
+                            let text = lines.iter().map(|l| sjis::decode_lossy(l)).collect::<Vec<_>>().join(" / ");
+                            report.warning(id, addr.orig, "pages", format!("{nlines} lines on {} pages{orig}{split}", pages.len()), text);
+                        }
 
-                            for line in lines {
-                                if yield_counter >= 3 {
-                                    eprintln!("warning: inserting yield");
-                                    yield_counter = 0;
-                                    new_actions.insert(addr, Action {
-                                        opcode: Action::OP_YIELD,
-                                        ..Default::default()
-                                    });
-                                    addr.sub += 1;
-                                }
+                        summary.pages += pages.len() - 1;
 
+                        let mut lines = lines.into_iter();
+                        for (i, &size) in pages.iter().enumerate() {
+                            if i > 0 {
+                                new_actions.insert(addr, Action {
+                                    opcode: Action::OP_YIELD,
+                                    ..Default::default()
+                                });
+                                addr.sub += 1;
+                            }
+                            for line in lines.by_ref().take(size) {
                                 new_actions.insert(addr, Action {
                                     opcode: Action::OP_LINE,
                                     params: vec![Parameter::LocalPointer(0)],
@@ -248,35 +357,234 @@ pub fn patch(mut db: Connection, args: Args) -> anyhow::Result<()> {
                                     ..Default::default()
                                 });
                                 addr.sub += 1;
-                                yield_counter += 1;
                             }
-                        } else {
-                            new_actions.append(&mut buf_actions);
                         }
                     } else {
-                        ensure!(buf_actions.is_empty());
+                        new_actions.append(&mut buf_actions);
                     }
                 } else {
-                    new_actions.append(&mut buf_actions);
+                    ensure!(buf_actions.is_empty());
                 }
                 ensure!(buf_actions.is_empty());
+                if let Action { call: false, opcode: Action::OP_CHOICE, ref params, .. } = act {
+                    if let Some(translation) = ctls.remove(&addr.orig) {
+                        ensure!(matches!(params[..], [Parameter::LocalPointer(0), Parameter::Value(_)]), "bad choice: params = {params:08X?}");
+                        match encode_translation(&translation, glyphs, &widths.markup).and_then(|enc| fit_line(&enc, widths).map(|()| enc)) {
+                            Ok(enc) => act.data = encode_string(&enc)?.freeze(),
+                            Err(e) => report.error(id, addr.orig, "choice", e, translation)
+                        }
+                    }
+                }
+                if let Some(strs) = stls.remove(&addr.orig) {
+                    ensure!(!strings::is_dialogue(&act), "string translation for dialogue at {}", addr.orig);
+                    let mut encoded = BTreeMap::new();
+                    for (param, translation) in strs {
+                        match encode_translation(&translation, glyphs, &widths.markup) {
+                            Ok(enc) => { encoded.insert(param, encode_string(&enc)?.freeze()); },
+                            Err(e) => report.error(id, addr.orig, "string", e, translation)
+                        }
+                    }
+                    if let Err(e) = act.replace_strings(&encoded) {
+                        report.error(id, addr.orig, "string", e, "");
+                    }
+                }
                 new_actions.insert(addr, act);
                 cur_addr = None;
             }
         }
     }
-    ensure!(tls.is_empty() && buf_actions.is_empty() && cur_addr.is_none());
+    ensure!(buf_actions.is_empty() && cur_addr.is_none(), "script ends in the middle of dialogue");
+    // reanalyze keeps lines and choices that are gone from the script for their translations
+    for (address, translation) in tls.into_iter().chain(ctls) {
+        report.warning(id, address, "orphaned", "no longer in the script", translation);
+    }
+    for (address, strs) in stls {
+        for translation in strs.into_values() {
+            report.warning(id, address, "orphaned", "no longer in the script", translation);
+        }
+    }
+
+    // pointers to a line that was replaced go to the start of its entry instead
+    for act in new_actions.values_mut() {
+        act.retarget(&replaced);
+    }
 
     let stcm2 = Stcm2 {
         actions: new_actions,
         ..stcm2
     };
 
-    let refile = format::to_bytes(stcm2)?;
+    let refile = format::to_bytes(stcm2.clone())?.freeze();
+
+    let issues = verify::verify(&stcm2, refile.clone())?;
+    if !issues.is_empty() {
+        for issue in issues.iter() {
+            report.error(id, issue.addr.orig, "verify", format!("action {}: {}", issue.addr.sub, issue.msg), "");
+        }
+        bail!("patched script failed verification with {} issues", issues.len());
+    }
+
+    let errors = report.errors();
+    if errors > 0 && !env.fallback {
+        bail!("{errors} entries could not be patched; pass --fallback to keep them in japanese");
+    }
+
+    summary.bytes = refile.len() as isize - orig_len as isize;
+    Ok((refile, summary))
+}
+
+pub fn patch(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let mut tx = db.transaction()?;
+    tx.set_drop_behavior(DropBehavior::Rollback);
+
+    // all means every script with translations in the chosen sessions
+    let ids = match args.ids {
+        Selection::All | Selection::Auto => {
+            let placeholders = vec!["?"; args.sessions.len()].join(", ");
+            let ids = tx.prepare(&format!("
+                SELECT scriptid FROM translations WHERE session IN ({placeholders})
+                UNION SELECT scriptid FROM choicetranslations WHERE session IN ({placeholders})
+                UNION SELECT scriptid FROM stringtranslations WHERE session IN ({placeholders})
+            "))?
+                .query_map(params_from_iter(args.sessions.iter().cycle().take(args.sessions.len() * 3)), |row| row.get(0))?
+                .collect::<Result<Vec<u32>, _>>()?;
+            ensure!(!ids.is_empty(), "no scripts have translations in {}", args.sessions.join(", "));
+            Selection::Ranges(ids.into_iter().map(|id| id..=id).collect())
+        },
+        ref ids => ids.clone()
+    };
+
+    let env = Env {
+        characters: Registry::load(&tx)?,
+        widths: Widths::load(&tx)?,
+        glyphs: Glyphs::load(&tx)?,
+        relocations: pointers::catalog(&tx)?,
+        fallback: args.fallback
+    };
 
-    tx.execute("INSERT OR REPLACE INTO patchedscripts(id, script) VALUES (?, ?)", (args.id, &refile[..]))?;
+    let mut report = Report::default();
+    let jobs = super::load(&tx, &ids)?.into_iter().map(|(id, file)| {
+        let mut script_report = Report::default();
+        let tls = load_translations(&tx, id, &args.sessions, &env.widths.markup, &mut script_report)?;
+        Ok((id, file, tls, script_report))
+    }).collect::<anyhow::Result<Vec<_>>>()?;
+
+    // don't let one script take down the rest
+    let results = jobs.into_par_iter()
+        .map(|(id, file, tls, mut script_report)| {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| patch_script(id, file, tls, &env, &mut script_report)))
+                .unwrap_or_else(|_| Err(anyhow!("patcher panicked")));
+            (id, res, script_report)
+        })
+        .collect::<Vec<_>>();
+
+    let mut failed = Vec::new();
+    for (id, res, script_report) in results {
+        report.extend(script_report);
+        match res {
+            Ok((file, summary)) => {
+                tx.execute("INSERT OR REPLACE INTO patchedscripts(id, script) VALUES (?, ?)", (id, &file[..]))?;
+                report.patched(id, summary);
+            },
+            Err(e) => {
+                report.failed(id, &e);
+                failed.push(id);
+            }
+        }
+    }
+    report.print(args.report, args.no_sources);
+
+    if !failed.is_empty() && !args.keep_going {
+        bail!("{} scripts failed: {failed:?}; nothing was patched (pass --keep-going to patch the rest)", failed.len());
+    }
+
+    if args.dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
 
-    tx.commit()?;
+    if !failed.is_empty() {
+        bail!("{} scripts failed: {failed:?}", failed.len());
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(text: &str) -> anyhow::Result<Vec<String>> {
+        let enc = Glyphs::default().encode(text)?;
+        let lines = split_lines_intelligent(&tokenize(&enc).collect::<Vec<_>>(), &Widths::default())?;
+        Ok(lines.iter().map(|line| sjis::decode_lossy(line)).collect())
+    }
+
+    #[test]
+    fn split_counts_placeholder_width() {
+        // 8 characters as written, but the player's name takes up to 10 cells
+        let word = "x".repeat(35);
+        assert_eq!(split(&format!("{} {word}", markup::PLAYER_NAME)).unwrap(), [markup::PLAYER_NAME, &word]);
+        let word = "x".repeat(34);
+        assert_eq!(split(&format!("{} {word}", markup::PLAYER_NAME)).unwrap(), [format!("{} {word}", markup::PLAYER_NAME)]);
+    }
+
+    #[test]
+    fn split_rejects_overlong_word() {
+        let word = "x".repeat(MAX_LINE_CELLS + 1);
+        let err = split(&format!("Hello {word} there")).unwrap_err();
+        assert!(err.to_string().contains("too wide for a line"), "{err}");
+        assert_eq!(split(&format!("Hello {} there", &word[1..])).unwrap(), ["Hello", &word[1..], "there"]);
+    }
+
+    #[test]
+    fn patch_choices_that_fit() {
+        let choice = |text: &[u8]| Action {
+            opcode: Action::OP_CHOICE,
+            params: vec![Parameter::LocalPointer(0), Parameter::Value(1)],
+            data: encode_string(text).unwrap().freeze(),
+            ..Default::default()
+        };
+        let stcm2 = Stcm2 {
+            tag: Bytes::from_static(&[0; 27]),
+            global_data: Bytes::new(),
+            actions: [choice(b"yes"), choice(b"no"), Action::default()].into_iter()
+                .enumerate()
+                .map(|(i, act)| (Address { orig: i as u32, sub: 0 }, act))
+                .collect()
+        };
+        let file = format::to_bytes(stcm2).unwrap().freeze();
+        let addrs = format::from_bytes(file.clone()).unwrap().actions.into_keys().map(|addr| addr.orig).collect::<Vec<_>>();
+
+        let tls = Translations {
+            lines: HashMap::new(),
+            choices: HashMap::from([(addrs[0], "Go".to_owned()), (addrs[1], "Stay here and wait for the others to come back".to_owned())]),
+            strings: HashMap::new(),
+            orig_lines: HashMap::new(),
+            nosplit: HashSet::new()
+        };
+        let env = Env { characters: Registry::default(), widths: Widths::default(), glyphs: Glyphs::default(), relocations: Vec::new(), fallback: true };
+        let mut report = Report::default();
+        let (patched, _) = patch_script(100, file, tls, &env, &mut report).unwrap();
+
+        let texts = format::from_bytes(patched).unwrap().actions.into_values()
+            .take(2)
+            .map(|act| format::decode_string(0, act.data).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, [&b"Go"[..], &b"no"[..]]);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!((report.problems[0].address, report.problems[0].kind), (addrs[1], "choice"));
+    }
+
+    #[test]
+    fn paginate_no_lines() {
+        assert_eq!(paginate(&[]), [0]);
+    }
+
+    #[test]
+    fn paginate_between_sentences() {
+        let lines = ["One.", "Two.", "Three and", "four.", "Five."].map(|l| l.as_bytes().to_vec());
+        assert_eq!(paginate(&lines), [2, 3]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use bytes::Bytes;
+use rusqlite::{Connection, DropBehavior};
+
+use super::{Args, format::{self, Stcm2}};
+
+#[derive(Clone, Debug, Default)]
+struct Field {
+    // actions with this opcode whose data is long enough to hold the field
+    total: usize,
+    // field equals the address of an action
+    starts: usize,
+    // field lands inside the code section, but not on an action
+    inside: usize,
+    sample: Option<(u32, u32)>
+}
+
+/// Reads the relocatable data fields registered in the database as (opcode, offset) pairs.
+pub fn catalog(db: &Connection) -> anyhow::Result<Vec<(u32, u32)>> {
+    let mut stmt = db.prepare("SELECT opcode, offset FROM relocations")?;
+    let catalog = stmt.query_map((), |row| row.try_into())?.collect::<Result<_, _>>()?;
+    Ok(catalog)
+}
+
+fn scan(stcm2: &Stcm2) -> BTreeMap<(u32, u32), Field> {
+    let starts = stcm2.actions.keys().map(|addr| addr.orig).collect::<BTreeSet<_>>();
+    let (Some(&first), Some(last)) = (starts.first(), stcm2.actions.last_key_value()) else { return BTreeMap::new() };
+    let end = last.0.orig + 16 + 12*last.1.params.len() as u32 + last.1.data.len() as u32;
+
+    let mut fields = BTreeMap::<(u32, u32), Field>::new();
+    for (addr, act) in stcm2.actions.iter() {
+        if act.call { continue }
+        for (i, chunk) in act.data.chunks_exact(4).enumerate() {
+            let offset = 4*i as u32;
+            let value = u32::from_le_bytes(chunk.try_into().unwrap());
+            let field = fields.entry((act.opcode, offset)).or_default();
+            field.total += 1;
+            if starts.contains(&value) {
+                field.starts += 1;
+                field.sample.get_or_insert((addr.orig, value));
+            } else if value >= first && value < end {
+                field.inside += 1;
+                field.sample.get_or_insert((addr.orig, value));
+            }
+        }
+    }
+    fields.retain(|_, f| f.starts > 0 || f.inside > 0);
+    fields
+}
+
+pub fn pointers(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let id = args.ids.single()?;
+    let mut tx = db.transaction()?;
+    tx.set_drop_behavior(DropBehavior::Commit);
+
+    let file = tx.query_row("SELECT script FROM scripts WHERE id = ?", (id,), |row| Ok(Bytes::copy_from_slice(row.get_ref(0)?.as_blob()?)))?;
+    let stcm2 = format::from_bytes(file)?;
+    let known = catalog(&tx)?;
+
+    let fields = scan(&stcm2);
+    if fields.is_empty() {
+        println!("no hidden pointers found");
+    }
+
+    let mut stmt = tx.prepare("INSERT OR IGNORE INTO relocations(opcode, offset) VALUES (?, ?)")?;
+    for (&(opcode, offset), field) in fields.iter() {
+        let Field { total, starts, inside, sample } = *field;
+        let (at, value) = sample.unwrap();
+        let status = if known.contains(&(opcode, offset)) {
+            "relocatable"
+        } else if starts == total {
+            // patch relocates every value of a registered field, so one that is sometimes 0 or a
+            // constant can't be registered
+            if args.mark {
+                stmt.execute((opcode, offset))?;
+                "marked"
+            } else {
+                "candidate"
+            }
+        } else {
+            "ambiguous"
+        };
+        println!("opcode {opcode:X} data+{offset}: {starts}/{total} point to actions, {inside}/{total} into code (e.g. {at:X} -> {value:X}) [{status}]");
+    }
+    drop(stmt);
+
+    if args.dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    Ok(())
+}
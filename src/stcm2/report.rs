@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use clap::ValueEnum;
+use serde_json::json;
+
+use super::patch::Summary;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    Json
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    // the entry can't be patched as translated
+    Error,
+    // the entry is patched, but should be looked at
+    Warning
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Error => "error",
+            Self::Warning => "warning"
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub script: u32,
+    pub address: u32,
+    pub severity: Severity,
+    pub kind: &'static str,
+    pub message: String,
+    // the offending text
+    pub text: String
+}
+
+/// Problems found while patching, per (script, address), the session each translation came from,
+/// per (script, address, param) as strings of one action can come from different sessions, and what
+/// became of each script.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub sources: Vec<(u32, u32, Option<usize>, String)>,
+    pub problems: Vec<Problem>,
+    pub scripts: Vec<(u32, Result<Summary, String>)>
+}
+
+impl Report {
+    pub fn source(&mut self, script: u32, address: u32, param: Option<usize>, session: &str) {
+        self.sources.push((script, address, param, session.to_owned()));
+    }
+
+    pub fn error(&mut self, script: u32, address: u32, kind: &'static str, message: impl Display, text: impl Into<String>) {
+        self.problems.push(Problem { script, address, severity: Severity::Error, kind, message: format!("{message:#}"), text: text.into() });
+    }
+
+    pub fn warning(&mut self, script: u32, address: u32, kind: &'static str, message: impl Display, text: impl Into<String>) {
+        self.problems.push(Problem { script, address, severity: Severity::Warning, kind, message: format!("{message:#}"), text: text.into() });
+    }
+
+    pub fn patched(&mut self, script: u32, summary: Summary) {
+        self.scripts.push((script, Ok(summary)));
+    }
+
+    pub fn failed(&mut self, script: u32, error: &anyhow::Error) {
+        self.scripts.push((script, Err(format!("{error:#}"))));
+    }
+
+    pub fn extend(&mut self, other: Report) {
+        self.sources.extend(other.sources);
+        self.problems.extend(other.problems);
+        self.scripts.extend(other.scripts);
+    }
+
+    pub fn errors(&self) -> usize {
+        self.problems.iter().filter(|p| p.severity == Severity::Error).count()
+    }
+
+    /// Prints the report; the table lists the source of every entry, or with `counts` only how many
+    /// entries came from each session.
+    pub fn print(&mut self, format: Format, counts: bool) {
+        self.sources.sort();
+        self.problems.sort_by_key(|p| (p.script, p.address));
+        self.scripts.sort_by_key(|&(script, _)| script);
+        match format {
+            Format::Table => {
+                if counts {
+                    let mut sessions = BTreeMap::<&str, usize>::new();
+                    for (_, _, _, session) in self.sources.iter() {
+                        *sessions.entry(session).or_default() += 1;
+                    }
+                    for (session, n) in sessions {
+                        println!("{session}: {n} entries");
+                    }
+                } else {
+                    for (script, address, param, session) in self.sources.iter() {
+                        match param {
+                            Some(param) => println!("{script} {address}.{param}: {session}"),
+                            None => println!("{script} {address}: {session}")
+                        }
+                    }
+                }
+                if !self.problems.is_empty() {
+                    let kind_width = self.problems.iter().map(|p| p.kind.len()).max().unwrap_or(0);
+                    println!("{:<6} {:<8} {:<7} {:<kind_width$} problem", "script", "address", "level", "kind");
+                    for p in self.problems.iter() {
+                        println!("{:<6} {:<8} {:<7} {:<kind_width$} {}", p.script, p.address, p.severity, p.kind, p.message);
+                        if !p.text.is_empty() {
+                            println!("{:<6} {:<8} {:<7} {:<kind_width$} > {}", "", "", "", "", p.text);
+                        }
+                    }
+                }
+                for (script, result) in self.scripts.iter() {
+                    match result {
+                        Ok(s) => println!("{script}: {} lines replaced, {} pages added, {:+} bytes", s.lines, s.pages, s.bytes),
+                        Err(e) => println!("{script}: failed: {e}")
+                    }
+                }
+            },
+            Format::Json => {
+                let problems = self.problems.iter().map(|p| json!({
+                    "script": p.script,
+                    "address": p.address,
+                    "severity": p.severity.to_string(),
+                    "kind": p.kind,
+                    "message": p.message,
+                    "text": p.text
+                })).collect::<Vec<_>>();
+                let sources = self.sources.iter().map(|(script, address, param, session)| json!({
+                    "script": script,
+                    "address": address,
+                    "param": param,
+                    "session": session
+                })).collect::<Vec<_>>();
+                let scripts = self.scripts.iter().map(|(script, result)| match result {
+                    Ok(s) => json!({ "script": script, "lines": s.lines, "pages": s.pages, "bytes": s.bytes }),
+                    Err(e) => json!({ "script": script, "error": e })
+                }).collect::<Vec<_>>();
+                println!("{}", serde_json::to_string_pretty(&json!({ "sources": sources, "problems": problems, "scripts": scripts })).unwrap());
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::{collections::{BTreeMap, BTreeSet}, panic};
+
+use anyhow::{anyhow, bail};
+use rayon::iter::{IntoParallelIterator as _, ParallelIterator as _};
+use rusqlite::{Connection, DropBehavior};
+
+use super::{Args, format::{self, Action, Parameter, Stcm2}, strings};
+
+#[derive(Clone, Debug, Default)]
+struct Field {
+    // actions with this opcode that have the parameter as a value
+    total: usize,
+    // values that are the id of another script
+    scripts: usize,
+    sample: Option<(u32, u32, u32)>
+}
+
+#[derive(Clone, Debug, Default)]
+struct Scene {
+    // scripts jumped to, in the order they appear
+    targets: Vec<u32>,
+    choices: bool
+}
+
+fn scan(id: u32, stcm2: &Stcm2, ids: &BTreeSet<u32>, fields: &mut BTreeMap<(u32, usize), Field>) {
+    for (addr, act) in stcm2.actions.iter() {
+        if act.call || strings::is_dialogue(act) { continue }
+        for (i, &param) in act.params.iter().enumerate() {
+            let Parameter::Value(value) = param else { continue };
+            let field = fields.entry((act.opcode, i)).or_default();
+            field.total += 1;
+            if value != id && ids.contains(&value) {
+                field.scripts += 1;
+                field.sample.get_or_insert((id, addr.orig, value));
+            }
+        }
+    }
+}
+
+fn scene(stcm2: &Stcm2, jumps: &[(u32, usize)], ids: &BTreeSet<u32>) -> Scene {
+    let mut scene = Scene::default();
+    for act in stcm2.actions.values() {
+        if act.call { continue }
+        if act.opcode == Action::OP_CHOICE {
+            scene.choices = true;
+        }
+        for &(opcode, i) in jumps.iter() {
+            if act.opcode != opcode { continue }
+            if let Some(&Parameter::Value(value)) = act.params.get(i) {
+                if ids.contains(&value) && !scene.targets.contains(&value) {
+                    scene.targets.push(value);
+                }
+            }
+        }
+    }
+    scene
+}
+
+/// Reads the parameters registered in the database as jumps to another script, as (opcode, param) pairs.
+fn jumps(db: &Connection) -> anyhow::Result<Vec<(u32, usize)>> {
+    let mut stmt = db.prepare("SELECT opcode, param FROM scenejumps")?;
+    let jumps = stmt.query_map((), |row| row.try_into())?.collect::<Result<_, _>>()?;
+    Ok(jumps)
+}
+
+pub fn scenes(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let mut tx = db.transaction()?;
+    tx.set_drop_behavior(DropBehavior::Commit);
+
+    let all = tx.prepare("SELECT id FROM scripts")?
+        .query_map((), |row| row.get(0))?
+        .collect::<Result<BTreeSet<u32>, _>>()?;
+    let files = super::load(&tx, &args.ids)?;
+    let parsed = files.into_par_iter()
+        .map(|(id, file)| (id, panic::catch_unwind(|| format::from_bytes(file))
+            .unwrap_or_else(|_| Err(anyhow!("parser panicked")))))
+        .collect::<Vec<_>>();
+
+    let mut scripts = BTreeMap::new();
+    let mut failed = Vec::new();
+    for (id, stcm2) in parsed {
+        match stcm2 {
+            Ok(stcm2) => { scripts.insert(id, stcm2); },
+            Err(e) => {
+                println!("{id}: failed: {e:#}");
+                failed.push(id);
+            }
+        }
+    }
+
+    // like pointers, but for values that name another script
+    let mut fields = BTreeMap::new();
+    for (&id, stcm2) in scripts.iter() {
+        scan(id, stcm2, &all, &mut fields);
+    }
+    fields.retain(|_, f| f.scripts > 0);
+
+    let mut known = jumps(&tx)?;
+    for (&(opcode, param), field) in fields.iter() {
+        let Field { total, scripts, sample } = *field;
+        let (from, at, to) = sample.unwrap();
+        let status = if known.contains(&(opcode, param)) {
+            "jump"
+        } else if scripts == total {
+            if args.mark {
+                tx.execute("INSERT OR IGNORE INTO scenejumps(opcode, param) VALUES (?, ?)", (opcode, param))?;
+                known.push((opcode, param));
+                "marked"
+            } else {
+                "candidate"
+            }
+        } else {
+            "ambiguous"
+        };
+        println!("opcode {opcode:X} param {param}: {scripts}/{total} are script ids (e.g. {from} {at:X} -> {to}) [{status}]");
+    }
+
+    if known.is_empty() {
+        println!("no jumps between scripts registered; pass --mark to register the candidates");
+    } else {
+        let scenes = scripts.iter()
+            .map(|(&id, stcm2)| (id, scene(stcm2, &known, &all)))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut preds = BTreeMap::<u32, BTreeSet<u32>>::new();
+        for (&id, scene) in scenes.iter() {
+            for &target in scene.targets.iter() {
+                preds.entry(target).or_default().insert(id);
+            }
+        }
+
+        // a route starts at every branch and at every script with no or several predecessors,
+        // and is named after its first script
+        let chained = |id: &u32| preds.get(id)
+            .filter(|p| p.len() == 1)
+            .and_then(|p| p.first())
+            .filter(|p| scenes.get(p).is_some_and(|s| s.targets.len() == 1))
+            .copied();
+        let routes = scenes.keys().map(|&id| {
+            let mut route = id;
+            let mut visited = BTreeSet::from([id]);
+            while let Some(pred) = chained(&route) {
+                if !visited.insert(pred) { break }
+                route = pred;
+            }
+            (id, route)
+        }).collect::<BTreeMap<_, _>>();
+
+        let mut stmt = tx.prepare("
+            INSERT INTO scenes(scriptid, route, predecessor, successor) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(scriptid) DO UPDATE SET
+                route = IFNULL(route, ?2),
+                predecessor = IFNULL(predecessor, ?3),
+                successor = IFNULL(successor, ?4)
+        ")?;
+        for (&id, scene) in scenes.iter() {
+            let predecessor = preds.get(&id).filter(|p| p.len() == 1).and_then(|p| p.first().copied());
+            let successor = match scene.targets[..] {
+                [successor] => Some(successor),
+                _ => None
+            };
+            let route = routes.get(&id).map(u32::to_string);
+
+            let mut desc = Vec::new();
+            if let Some(preds) = preds.get(&id) {
+                desc.push(format!("from {preds:?}"));
+            }
+            match scene.targets[..] {
+                [] => desc.push("ends".to_owned()),
+                [successor] => desc.push(format!("continues to {successor}")),
+                ref targets => desc.push(format!("branches to {targets:?}{}", if scene.choices { " after a choice" } else { "" }))
+            }
+            if let Some(ref route) = route {
+                desc.push(format!("route {route}"));
+            }
+            println!("{id}: {}", desc.join(", "));
+
+            if args.update {
+                // no opcode is known to hold the title, so that is left to the scene command
+                stmt.execute((id, route, predecessor, successor))?;
+            }
+        }
+        if !args.update {
+            println!("pass --update to store scenes; values set by hand are kept");
+        }
+    }
+
+    if args.dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    if !failed.is_empty() {
+        bail!("{} scripts failed: {failed:?}", failed.len());
+    }
+
+    Ok(())
+}
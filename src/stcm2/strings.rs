@@ -0,0 +1,170 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt::Display, panic, str::FromStr};
+
+use anyhow::{anyhow, bail, Context as _};
+use rayon::iter::{IntoParallelIterator as _, ParallelIterator as _};
+use rusqlite::{Connection, DropBehavior};
+
+use crate::sjis;
+
+use super::{Args, format::{self, Action, Parameter, Stcm2}};
+
+/// A string argument outside of dialogue, like a chapter title, a menu label or a file name.
+#[derive(Clone, Debug)]
+pub struct Str {
+    pub addr: u32,
+    pub param: usize,
+    // the callee's address for calls
+    pub opcode: u32,
+    pub call: bool,
+    pub text: String
+}
+
+/// A string parameter of an opcode (or of calls to a function), like `1A3.0` or `call:4F0.1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StringParam {
+    pub call: bool,
+    pub opcode: u32,
+    pub param: usize
+}
+
+impl FromStr for StringParam {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (call, rest) = match s.strip_prefix("call:") {
+            Some(rest) => (true, rest),
+            None => (false, s)
+        };
+        let (opcode, param) = rest.split_once('.').with_context(|| format!("bad string param {s}, expected OPCODE.PARAM"))?;
+        Ok(Self {
+            call,
+            opcode: u32::from_str_radix(opcode, 16).with_context(|| format!("bad opcode in {s}"))?,
+            param: param.parse().with_context(|| format!("bad param in {s}"))?
+        })
+    }
+}
+
+impl Display for StringParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.call {
+            write!(f, "call:{:X}.{}", self.opcode, self.param)
+        } else {
+            write!(f, "{:X}.{}", self.opcode, self.param)
+        }
+    }
+}
+
+/// Condition on a query over `strings` that keeps the params registered as displayed text; the
+/// rest are file names, labels and the like, which the engine looks up and must stay as they are.
+pub const REGISTERED: &str = "(strings.opcode, strings.call, strings.param) IN (SELECT opcode, call, param FROM stringparams)";
+
+/// Reads the string params registered as displayed text.
+fn registered(db: &Connection) -> anyhow::Result<BTreeSet<StringParam>> {
+    let mut stmt = db.prepare("SELECT call, opcode, param FROM stringparams")?;
+    let params = stmt.query_map((), |row| Ok(StringParam { call: row.get(0)?, opcode: row.get(1)?, param: row.get(2)? }))?
+        .collect::<Result<_, _>>()?;
+    Ok(params)
+}
+
+/// Whether the strings of this action are already handled as dialogue.
+pub fn is_dialogue(act: &Action) -> bool {
+    !act.call && matches!(act.opcode, Action::OP_SPEAKER | Action::OP_LINE | Action::OP_CHOICE)
+}
+
+/// Finds every local pointer parameter outside of dialogue that points to a valid Shift_JIS string.
+pub fn find(stcm2: &Stcm2) -> Vec<Str> {
+    let mut strs = Vec::new();
+    for (addr, act) in stcm2.actions.iter() {
+        if is_dialogue(act) { continue }
+        for (param, &p) in act.params.iter().enumerate() {
+            let Parameter::LocalPointer(off) = p else { continue };
+            let Ok((str, _)) = format::string_at(&act.data, off) else { continue };
+            let Ok(text) = sjis::decode(&str) else { continue };
+            if text.is_empty() || text.chars().any(char::is_control) { continue }
+            strs.push(Str { addr: addr.orig, param, opcode: act.opcode, call: act.call, text });
+        }
+    }
+    strs
+}
+
+impl Str {
+    fn key(&self) -> StringParam {
+        StringParam { call: self.call, opcode: self.opcode, param: self.param }
+    }
+}
+
+fn store(tx: &Connection, id: u32, strs: &[Str], registered: &BTreeSet<StringParam>) -> anyhow::Result<usize> {
+    let mut stmt = tx.prepare_cached("INSERT OR IGNORE INTO strings(scriptid, address, param, opcode, call, text) VALUES (?, ?, ?, ?, ?, ?)")?;
+    let mut stored = 0;
+    for s in strs.iter().filter(|s| registered.contains(&s.key())) {
+        stmt.execute((id, s.addr, s.param, s.opcode, s.call, &s.text))?;
+        stored += 1;
+    }
+    Ok(stored)
+}
+
+pub fn extract(mut db: Connection, args: Args) -> anyhow::Result<()> {
+    let mut tx = db.transaction()?;
+    tx.set_drop_behavior(DropBehavior::Commit);
+
+    let files = super::load(&tx, &args.ids)?;
+
+    let found = files.into_par_iter()
+        .map(|(id, file)| (id, panic::catch_unwind(|| Ok(find(&format::from_bytes(file)?)))
+            .unwrap_or_else(|_| Err(anyhow!("parser panicked")))))
+        .collect::<Vec<(u32, anyhow::Result<_>)>>();
+
+    let mut registered = registered(&tx)?;
+    for &param in args.text.iter() {
+        tx.execute("INSERT OR IGNORE INTO stringparams(opcode, call, param) VALUES (?, ?, ?)", (param.opcode, param.call, param.param))?;
+        registered.insert(param);
+    }
+
+    // (count, sample) for each param, to decide which ones display text
+    let mut params = BTreeMap::<StringParam, (usize, String)>::new();
+    let mut total = 0;
+    let mut failed = Vec::new();
+    for (id, strs) in found {
+        let res = strs.and_then(|strs| {
+            let mut sp = tx.savepoint()?;
+            sp.set_drop_behavior(DropBehavior::Rollback);
+            let stored = store(&sp, id, &strs, &registered)?;
+            sp.commit()?;
+            Ok((strs, stored))
+        });
+        match res {
+            Ok((strs, _)) if strs.is_empty() => (),
+            Ok((strs, stored)) => {
+                for s in strs.iter() {
+                    params.entry(s.key()).or_insert_with(|| (0, s.text.clone())).0 += 1;
+                }
+                println!("{id}: found {} strings, stored {stored}", strs.len());
+                total += stored;
+            },
+            Err(e) => {
+                println!("{id}: failed: {e:#}");
+                failed.push(id);
+            }
+        }
+    }
+    for (param, (n, sample)) in params.iter() {
+        let status = if registered.contains(param) { "text" } else { "candidate" };
+        println!("{param}: {n} strings (e.g. {sample:?}) [{status}]");
+    }
+    if registered.is_empty() {
+        println!("no string params registered; pass the ones that display text with --text, like --text 1A3.0,call:4F0.1");
+    }
+    println!("total: stored {total} strings");
+
+    if args.dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+    }
+
+    if !failed.is_empty() {
+        bail!("{} scripts failed: {failed:?}", failed.len());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::{iter, slice, str};
+
+use crate::{font::Widths, markup};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token<'a> {
+    Fullwidth([u8; 2]),
+    Halfwidth(u8),
+    Code(&'a [u8])
+}
+
+impl Token<'_> {
+    pub fn width(self, widths: &Widths) -> usize {
+        match self {
+            Self::Fullwidth(c) => widths.glyph(&c),
+            Self::Halfwidth(c) => widths.glyph(&[c]),
+            Self::Code(c) => widths.code(str::from_utf8(c).unwrap())
+        }
+    }
+
+    /// Width in halfwidth cells, which is what the game's line length limit counts.
+    pub fn cells(self, widths: &Widths) -> usize {
+        match self {
+            Self::Fullwidth(_) => 2,
+            Self::Halfwidth(_) => 1,
+            Self::Code(c) => widths.code_cells(str::from_utf8(c).unwrap())
+        }
+    }
+
+    pub fn rep(&self) -> &[u8] {
+        match self {
+            Self::Fullwidth(c) => c,
+            Self::Halfwidth(c) => slice::from_ref(c),
+            Self::Code(c) => c
+        }
+    }
+}
+
+pub fn tokenize(mut input: &[u8]) -> impl Iterator<Item = Token<'_>> {
+    iter::from_fn(move || {
+        if input.is_empty() {
+            None
+        } else if let Some(len) = markup::code_len(input) {
+            let (code, sl) = input.split_at(len);
+            input = sl;
+            Some(Token::Code(code))
+        } else if matches!(input[0], 0x81..=0x9F | 0xE0..=0xFC) {
+            let &[ch, cl, ref sl @ ..] = input else { panic!("sjis terminates early") };
+            input = sl;
+            Some(Token::Fullwidth([ch, cl]))
+        } else {
+            let &[c, ref sl @ ..] = input else { unreachable!() };
+            input = sl;
+            Some(Token::Halfwidth(c))
+        }
+    })
+}
+
+/// Display width of a Shift_JIS string.
+pub fn width(input: &[u8], widths: &Widths) -> usize {
+    tokenize(input).map(|t| t.width(widths)).sum()
+}
@@ -0,0 +1,181 @@
+use std::{collections::HashMap, fmt::Display, iter};
+
+use bytes::Bytes;
+
+use super::format::{self, Action, Address, Parameter, Stcm2};
+
+#[derive(Clone, Debug)]
+pub struct Issue {
+    pub addr: Address,
+    pub msg: String
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:X}.{}: {}", self.addr.orig, self.addr.sub, self.msg)
+    }
+}
+
+fn raw(param: Parameter, data_addr: u32) -> Option<u32> {
+    match param {
+        Parameter::LocalPointer(addr) => Some(data_addr + addr),
+        Parameter::Value(value) => Some(value),
+        Parameter::GlobalPointer(_) => None
+    }
+}
+
+pub fn check(
+    src_addr: Address,
+    src: &Action,
+    new_pos: u32,
+    new: &Action,
+    map: &HashMap<u32, Address>,
+    issues: &mut Vec<Issue>
+) {
+    let mut issue = |msg: String| issues.push(Issue { addr: src_addr, msg });
+    let points_to = |pos: u32, orig: u32| map.get(&pos) == Some(&Address { orig, sub: 0 });
+
+    match (src.call, new.call) {
+        (true, true) => if !points_to(new.opcode, src.opcode) {
+            issue(format!("call to {:X} now goes to {:X} ({:X?})", src.opcode, new.opcode, map.get(&new.opcode)));
+        },
+        (false, false) => if src.opcode != new.opcode {
+            issue(format!("opcode changed from {:X} to {:X}", src.opcode, new.opcode));
+        },
+        _ => issue("call flag changed".to_owned())
+    }
+
+    if src.export.as_ref().map(|e| &e[..]) != new.export.as_ref().map(|e| &e[..]) {
+        issue(format!("export changed from {:?} to {:?}", src.export, new.export));
+    }
+
+    if src.params.len() != new.params.len() {
+        issue(format!("parameter count changed from {} to {}", src.params.len(), new.params.len()));
+        return;
+    }
+    let src_data_addr = src_addr.orig + 16 + 12*src.params.len() as u32;
+    let new_data_addr = new_pos + 16 + 12*new.params.len() as u32;
+    for (i, (&sp, &np)) in iter::zip(src.params.iter(), new.params.iter()).enumerate() {
+        let ok = match (sp, np) {
+            (Parameter::GlobalPointer(s), Parameter::GlobalPointer(n)) => points_to(n, s),
+            (Parameter::LocalPointer(s), Parameter::LocalPointer(n)) => s == n,
+            (Parameter::Value(s), Parameter::Value(n)) => s == n,
+            // a value may happen to land in the data of the shifted action, which is harmless
+            (Parameter::Value(s), np) => raw(np, new_data_addr) == Some(s),
+            (Parameter::LocalPointer(_), _) | (Parameter::GlobalPointer(_), _) => false
+        };
+        if !ok {
+            issue(format!("parameter {i} was {sp:X?} (data at {src_data_addr:X}), is now {np:X?}"));
+        }
+    }
+
+    if src.data.len() != new.data.len() {
+        issue(format!("data length changed from {} to {}", src.data.len(), new.data.len()));
+        return;
+    }
+    let mut expected = src.data.to_vec();
+    for &(offset, target) in src.relocs.iter() {
+        let offset = offset as usize;
+        let value = u32::from_le_bytes(new.data[offset..offset+4].try_into().unwrap());
+        if !points_to(value, target) {
+            issue(format!("pointer at data+{offset} to {target:X} now goes to {value:X} ({:X?})", map.get(&value)));
+        }
+        expected[offset..offset+4].copy_from_slice(&new.data[offset..offset+4]);
+    }
+    if expected != new.data {
+        issue("data changed".to_owned());
+    }
+}
+
+/// Re-parses the serialized `output` of `source` and checks that every reference
+/// still points at the equivalent action.
+pub fn verify(source: &Stcm2, output: Bytes) -> anyhow::Result<Vec<Issue>> {
+    let output = format::from_bytes(output)?;
+    let mut issues = Vec::new();
+
+    if source.actions.len() != output.actions.len() {
+        issues.push(Issue {
+            addr: Address { orig: 0, sub: 0 },
+            msg: format!("action count changed from {} to {}", source.actions.len(), output.actions.len())
+        });
+        return Ok(issues);
+    }
+
+    let map = iter::zip(source.actions.keys(), output.actions.keys())
+        .map(|(&src, &new)| (new.orig, src))
+        .collect::<HashMap<_, _>>();
+
+    for ((&src_addr, src), (&new_addr, new)) in iter::zip(source.actions.iter(), output.actions.iter()) {
+        check(src_addr, src, new_addr.orig, new, &map, &mut issues);
+    }
+
+    if source.tag != output.tag {
+        issues.push(Issue { addr: Address { orig: 0, sub: 0 }, msg: "tag changed".to_owned() });
+    }
+    if source.global_data != output.global_data {
+        issues.push(Issue { addr: Address { orig: 0, sub: 0 }, msg: "global data changed".to_owned() });
+    }
+
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    // a script whose first action holds a pointer to the last one in its data
+    fn script() -> Stcm2 {
+        let action = |opcode, data: &[u32], relocs| Action {
+            opcode,
+            data: data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            relocs,
+            ..Default::default()
+        };
+        Stcm2 {
+            tag: Bytes::from_static(&[0; 27]),
+            global_data: Bytes::new(),
+            actions: BTreeMap::from([
+                (Address { orig: 0x100, sub: 0 }, action(1, &[0x300], vec![(0, 0x300)])),
+                (Address { orig: 0x200, sub: 0 }, action(2, &[5], vec![])),
+                (Address { orig: 0x300, sub: 0 }, action(3, &[], vec![]))
+            ])
+        }
+    }
+
+    #[test]
+    fn relocated_pointer_is_fine() {
+        let mut source = script();
+        // growing an action moves the target of the pointer
+        source.actions.get_mut(&Address { orig: 0x200, sub: 0 }).unwrap().data = Bytes::from_static(&[0; 32]);
+        let output = format::to_bytes(source.clone()).unwrap().freeze();
+        assert!(verify(&source, output).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dangling_pointer_is_reported() {
+        let source = script();
+        let mut output = format::to_bytes(source.clone()).unwrap();
+        let first = *format::from_bytes(output.clone().freeze()).unwrap().actions.keys().next().unwrap();
+        let data = first.orig as usize + 16;
+        output[data..data+4].copy_from_slice(&0x1234u32.to_le_bytes());
+
+        let issues = verify(&source, output.freeze()).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].addr, Address { orig: 0x100, sub: 0 });
+        assert!(issues[0].msg.starts_with("pointer at data+0 to 300 now goes to 1234"), "{}", issues[0]);
+    }
+
+    #[test]
+    fn changed_action_count_is_reported() {
+        let source = script();
+        let mut patched = source.clone();
+        patched.actions.insert(Address { orig: 0x200, sub: 1 }, Action { opcode: Action::OP_YIELD, ..Default::default() });
+        let output = format::to_bytes(patched).unwrap().freeze();
+
+        let issues = verify(&source, output).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].msg, "action count changed from 3 to 4");
+    }
+}
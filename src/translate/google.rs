@@ -1,74 +1,55 @@
-use std::iter;
 use anyhow::{ensure, Context as _};
 use reqwest::Client;
-use rusqlite::{Connection, DropBehavior};
 use serde_json::{json, Value};
 
+use super::{Pending, Translated};
+
 #[derive(Clone, Debug)]
-pub struct Translator(String);
+pub struct Translator {
+    api_key: String,
+    endpoint: String
+}
 
 impl Translator {
-    pub fn new(api_key: String) -> Self {
-        Self(api_key)
+    pub fn new(api_key: String, endpoint: String) -> Self {
+        Self { api_key, endpoint }
     }
 }
 
-impl Translator {
-    pub async fn translate(&self, client: Client, db: &mut Connection, script: u32) -> anyhow::Result<()> {
-        let lines = db.prepare_cached("
-            SELECT address, line
-            FROM lines
-            WHERE scriptid = ?1
-                AND ('google', ?1, address) NOT IN
-                    (SELECT session, scriptid, address FROM translations)
-        ")?.query_map((script,), |row| row.try_into())?.collect::<Result<Vec<(u32, String)>, _>>()?;
-
-        for chunk in lines.chunks(128) {
-            println!("translating {} lines", chunk.len());
-
-            let mut tx = db.transaction()?;
-            tx.set_drop_behavior(DropBehavior::Commit);
-
-            let res = client
-                .post("https://translation.googleapis.com/language/translate/v2")
-                .header("X-Goog-Api-Key", &self.0)
-                .json(&json!({
-                    "q": chunk.iter().map(|(_, l)| l.as_ref()).collect::<Vec<&str>>(),
-                    "target": "en",
-                    "format": "text",
-                    "source": "ja"
-                }))
-                .send().await?;
+impl super::Translator for Translator {
+    fn session(&self) -> &str {
+        "google"
+    }
 
-            ensure!(res.status().is_success(), "bad response: {}\n{}", format!("{res:?}"), res.text().await?);
+    fn batch_size(&self) -> usize {
+        128
+    }
 
-            let res = res.json::<Value>().await?;
+    // google translates every text on its own, so the context is not used
+    async fn translate(&self, client: &Client, _context: &[Translated], items: &[Pending]) -> anyhow::Result<Vec<Option<String>>> {
+        println!("translating {} lines", items.len());
 
-            let tls = res
-                .pointer("/data/translations").context("no translations")?
-                .as_array().context("translations is not array")?;
+        let res = client
+            .post(format!("{}/language/translate/v2", self.endpoint.trim_end_matches('/')))
+            .header("X-Goog-Api-Key", &self.api_key)
+            .json(&json!({
+                "q": items.iter().map(|item| &item.text).collect::<Vec<_>>(),
+                "target": "en",
+                "format": "text",
+                "source": "ja"
+            }))
+            .send().await?;
 
-            {
-                let mut stmt = tx.prepare_cached("INSERT INTO translations(session, scriptid, address, translation) VALUES('google', ?, ?, ?)")?;
+        ensure!(res.status().is_success(), "bad response: {}\n{}", format!("{res:?}"), res.text().await?);
 
-                for ((addr, _orig), tl) in iter::zip(chunk, tls) {
-                    // make insertions resilient; try to salvage as much data as possible
-                    match tl.pointer("/translatedText").and_then(Value::as_str) {
-                        None => eprintln!("warning: script {script} addr {addr} has an invalid or missing translatedText"),
-                        Some(tl) => {
-                            if let Err(e) = stmt.execute((script, addr, tl)) {
-                                eprintln!("warning: script {script} addr {addr} failed to save");
-                                eprintln!("tl: {tl}");
-                                eprintln!("error: {e}");
-                            }
-                        }
-                    }
-                }
-            }
+        let res = res.json::<Value>().await?;
 
-            tx.commit()?;
-        }
+        let tls = res
+            .pointer("/data/translations").context("no translations")?
+            .as_array().context("translations is not array")?;
 
-        Ok(())
+        Ok((0..items.len())
+            .map(|i| tls.get(i).and_then(|tl| tl.pointer("/translatedText")).and_then(Value::as_str).map(str::to_owned))
+            .collect())
     }
 }
@@ -1,19 +1,136 @@
 #![allow(clippy::write_with_newline)]
 
-mod characters;
+use std::{cell::Cell, collections::HashSet, fmt::{Display, Write as _}};
 
-use std::{collections::HashSet, fmt::{Display, Write as _}};
-
-use anyhow::Context;
-use reqwest::Client;
+use anyhow::{anyhow, ensure, Context};
+use clap::ValueEnum;
+use reqwest::{Client, RequestBuilder};
 use serde_json::json;
-use rusqlite::{Connection, DropBehavior};
+use rusqlite::Connection;
+
+use crate::{characters::{Character, EnSpeaker, Registry}, config};
+
+use super::{Kind, Pending, Translated};
+
+/// The kind of server the endpoint is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Api {
+    /// llama.cpp's own /tokenize and /completion, with a grammar forcing the speaker
+    #[default]
+    Llamacpp,
+    /// OpenAI-style /v1/completions
+    Completions,
+    /// OpenAI-style /v1/chat/completions
+    Chat
+}
+
+/// Options for the LLM backend that override the config table for one invocation.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Overrides {
+    #[arg(long, value_enum, help = "kind of server (llm_api)")]
+    api: Option<Api>,
+    #[arg(long, help = "base url of the server, with /v1 for the openai apis (llm_endpoint)")]
+    endpoint: Option<String>,
+    #[arg(long, help = "model to ask for, needed by most openai-compatible servers (llm_model)")]
+    model: Option<String>,
+    #[arg(long, help = "session to store translations in (llm_session)")]
+    session: Option<String>,
+    #[arg(long, help = "sampling temperature (llm_temperature)")]
+    temperature: Option<f64>,
+    #[arg(long, help = "top-p sampling (llm_top_p)")]
+    top_p: Option<f64>,
+    #[arg(long, help = "most tokens to generate per line (llm_max_tokens)")]
+    max_tokens: Option<usize>,
+    #[arg(long, help = "context window of the model in tokens (llm_context)")]
+    context: Option<usize>,
+    #[arg(long, help = "stop string, can be repeated (llm_stop, a json array)")]
+    stop: Vec<String>
+}
+
+/// Where and how to ask the model, from the `llm_*` config options. Temperature and top-p are left to
+/// the server unless set. The api key only comes from the config, so it stays out of shell history.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub api: Api,
+    pub endpoint: String,
+    pub model: Option<String>,
+    pub api_key: Option<String>,
+    pub session: String,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: usize,
+    pub context: usize,
+    pub stop: Vec<String>
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            api: Api::default(),
+            endpoint: "http://127.0.0.1:8080".to_owned(),
+            model: None,
+            api_key: None,
+            session: "vntl-greedy-20240823".to_owned(),
+            temperature: None,
+            top_p: None,
+            max_tokens: 48,
+            context: 8192,
+            stop: Vec::new()
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(db: &Connection, overrides: Overrides) -> anyhow::Result<Self> {
+        let default = Self::default();
+        let stop = match config::get::<String>(db, "llm_stop")? {
+            Some(stop) => serde_json::from_str(&stop).context("llm_stop must be a json array of strings")?,
+            None => default.stop
+        };
+        let api = match overrides.api {
+            Some(api) => api,
+            None => match config::get::<String>(db, "llm_api")? {
+                Some(api) => Api::from_str(&api, true).map_err(|e| anyhow!("bad llm_api: {e}"))?,
+                None => default.api
+            }
+        };
+        let settings = Self {
+            api,
+            endpoint: overrides.endpoint.or(config::get(db, "llm_endpoint")?).unwrap_or(default.endpoint),
+            model: overrides.model.or(config::get(db, "llm_model")?),
+            api_key: config::get(db, "llm_api_key")?,
+            session: overrides.session.or(config::get(db, "llm_session")?).unwrap_or(default.session),
+            temperature: overrides.temperature.or(config::get(db, "llm_temperature")?),
+            top_p: overrides.top_p.or(config::get(db, "llm_top_p")?),
+            max_tokens: overrides.max_tokens.or(config::get(db, "llm_max_tokens")?).unwrap_or(default.max_tokens),
+            context: overrides.context.or(config::get(db, "llm_context")?).unwrap_or(default.context),
+            stop: if overrides.stop.is_empty() { stop } else { overrides.stop }
+        };
+        ensure!(settings.max_tokens < settings.context, "max tokens ({}) must be less than the context ({})", settings.max_tokens, settings.context);
+        Ok(settings)
+    }
 
-use characters::{decode_jp_speaker, Character, EnSpeaker};
+    fn url(&self, path: &str) -> String {
+        format!("{}/{path}", self.endpoint.trim_end_matches('/'))
+    }
+
+    fn post(&self, client: &Client, url: String) -> RequestBuilder {
+        let req = client.post(url);
+        match self.api_key {
+            Some(ref key) => req.bearer_auth(key),
+            None => req
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Translator {
-    session: String
+    settings: Settings,
+    characters: Registry,
+    // set once the openai-compatible server turns out to have no tokenize endpoint
+    approximate: Cell<bool>,
+    // how many of the oldest lines of the context no longer fit in the prompt
+    dropped: Cell<usize>
 }
 
 #[derive(Clone, Debug)]
@@ -39,21 +156,21 @@ impl Display for Seen {
     }
 }
 
-fn build_header(seen: &[Seen], next_speaker: Option<&str>, next_line: &str) -> anyhow::Result<String> {
+fn build_header(characters: &Registry, seen: &[Seen], next_speaker: Option<&str>, next_line: &str) -> anyhow::Result<String> {
     let mut cs = seen.iter()
         .filter_map(|s| s.speaker.as_ref())
         .map(|(j, _)| j.as_str())
         .chain(next_speaker)
-        .filter_map(|j| match decode_jp_speaker(j) {
+        .filter_map(|j| match characters.decode(j) {
             Ok(EnSpeaker::Str(_)) => None,
             Ok(EnSpeaker::Character(c)) => Some(Ok(c)),
             Err(e) => Some(Err(e))
         })
         .collect::<anyhow::Result<HashSet<&Character>>>()?;
 
-    for c in characters::CHARACTERS.iter() {
+    for c in characters.characters.iter() {
         if cs.contains(c) { continue }
-        if next_line.contains(c.jpspeaker) {
+        if next_line.contains(c.jpspeaker.as_str()) {
             cs.insert(c);
             continue
         }
@@ -75,8 +192,8 @@ fn build_header(seen: &[Seen], next_speaker: Option<&str>, next_line: &str) -> a
     Ok(header)
 }
 
-fn build_prompt(seen: &[Seen], next_speaker: Option<&str>, next_line: &str) -> anyhow::Result<String> {
-    let mut prompt = build_header(seen, next_speaker, next_line)?;
+fn build_prompt(characters: &Registry, seen: &[Seen], next_speaker: Option<&str>, next_line: &str) -> anyhow::Result<String> {
+    let mut prompt = build_header(characters, seen, next_speaker, next_line)?;
     for s in seen {
         write!(prompt, "{s}\n")?;
     }
@@ -111,10 +228,14 @@ impl Display for MaxTokensReachedError {
 
 impl std::error::Error for MaxTokensReachedError {}
 
-async fn tokenize(client: &Client, content: &str) -> anyhow::Result<Vec<u32>> {
-    client
-        .post("http://127.0.0.1:8080/tokenize")
-        .json(&json!({ "content": content }))
+async fn tokenize(client: &Client, settings: &Settings, url: String, content: &str) -> anyhow::Result<Vec<u32>> {
+    // llama.cpp reads content, vLLM reads prompt and model
+    let mut req = json!({ "content": content, "prompt": content });
+    if let Some(ref model) = settings.model {
+        req["model"] = json!(model);
+    }
+    settings.post(client, url)
+        .json(&req)
         .send().await?.error_for_status()?
         .json::<serde_json::Value>().await?
         .pointer("/tokens").context("no tokens")?
@@ -122,14 +243,31 @@ async fn tokenize(client: &Client, content: &str) -> anyhow::Result<Vec<u32>> {
         .iter().map(|n| Ok(n.as_u64().context("not number")?.try_into()?)).collect()
 }
 
-async fn get_completion(client: &Client, prompt: &[u32], speaker: &str) -> anyhow::Result<String> {
-    let resp = client
-        .post("http://127.0.0.1:8080/completion")
-        .json(&if !speaker.is_empty() { json!({
-             "prompt": prompt,
-             "n_predict": 48,
-             "grammar": format!("root ::= \"{speaker}\" [^\\n]*")
-        }) } else { json!({ "prompt": prompt, "n_predict": 48 }) })
+/// Roughly what the llama 3 tokenizer makes of the prompt: about a token per kana or kanji, and a few
+/// characters of english per token. Other tokenizers may well need more, so a quarter is added on top
+/// for the prompt to still fit the context.
+fn approximate_tokens(s: &str) -> usize {
+    let ascii = s.chars().filter(char::is_ascii).count();
+    let tokens = s.chars().count() - ascii + ascii.div_ceil(3);
+    tokens + tokens.div_ceil(4)
+}
+
+async fn get_completion(client: &Client, settings: &Settings, prompt: &[u32], speaker: &str) -> anyhow::Result<String> {
+    let mut req = json!({ "prompt": prompt, "n_predict": settings.max_tokens });
+    if !speaker.is_empty() {
+        req["grammar"] = json!(format!("root ::= \"{speaker}\" [^\\n]*"));
+    }
+    if let Some(temperature) = settings.temperature {
+        req["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = settings.top_p {
+        req["top_p"] = json!(top_p);
+    }
+    if !settings.stop.is_empty() {
+        req["stop"] = json!(settings.stop);
+    }
+    let resp = settings.post(client, settings.url("completion"))
+        .json(&req)
         .send().await?.error_for_status()?
         .json::<serde_json::Value>().await?;
     
@@ -145,99 +283,153 @@ async fn get_completion(client: &Client, prompt: &[u32], speaker: &str) -> anyho
     if truncated {
         Err(MaxTokensReachedError(content).into())
     } else {
-        Ok(content)
+        Ok(content.strip_prefix(speaker).unwrap_or(&content).trim().to_owned())
+    }
+}
+
+/// Asks an OpenAI-compatible server. Without a grammar, the speaker is put at the end of the prompt
+/// for completions and asked for in the instructions for chat, and the line ends at a newline.
+async fn get_openai_completion(client: &Client, settings: &Settings, prompt: &str, speaker: &str) -> anyhow::Result<String> {
+    let (path, mut req) = match settings.api {
+        Api::Chat => {
+            // a chat template brings its own special tokens
+            let prompt = prompt.replace("<|begin_of_text|>", "").replace("<|end_of_text|>", "");
+            let mut system = "Translate the last line of this visual novel script from Japanese to English, \
+                in the same format as the lines before it. Reply with the translated line only".to_owned();
+            if !speaker.is_empty() {
+                write!(system, ", starting with `{speaker}`")?;
+            }
+            system.push('.');
+            ("chat/completions", json!({ "messages": [
+                { "role": "system", "content": system },
+                { "role": "user", "content": prompt }
+            ] }))
+        },
+        _ => ("completions", json!({ "prompt": format!("{prompt}{speaker}") }))
+    };
+    req["max_tokens"] = json!(settings.max_tokens);
+    if let Some(ref model) = settings.model {
+        req["model"] = json!(model);
+    }
+    if let Some(temperature) = settings.temperature {
+        req["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = settings.top_p {
+        req["top_p"] = json!(top_p);
+    }
+    req["stop"] = if settings.stop.is_empty() { json!(["\n"]) } else { json!(settings.stop) };
+    let resp = settings.post(client, settings.url(path))
+        .json(&req)
+        .send().await?.error_for_status()?
+        .json::<serde_json::Value>().await?;
+
+    let choice = resp.pointer("/choices/0").context("no choices")?;
+    let content = match settings.api {
+        Api::Chat => choice.pointer("/message/content"),
+        _ => choice.pointer("/text")
+    }.context("no content")?.as_str().context("content is not string")?;
+    let content = content.trim_start().lines().next().unwrap_or_default();
+
+    if choice.pointer("/finish_reason").and_then(|r| r.as_str()) == Some("length") {
+        Err(MaxTokensReachedError(content.to_owned()).into())
+    } else {
+        Ok(content.strip_prefix(speaker).unwrap_or(content).trim().to_owned())
     }
 }
 
 impl Translator {
-    pub fn new(session: String) -> anyhow::Result<Self> {
-        Ok(Self { session })
-    }
-
-    pub async fn translate(&self, cli: Client, db: &mut Connection, script: u32) -> anyhow::Result<()> {
-        let mut seen = Vec::new();
-
-        let mut tx = db.transaction()?;
-        tx.set_drop_behavior(DropBehavior::Commit);
-        let mut stmt = tx.prepare_cached("
-            SELECT lines.address, lines.speaker, lines.line, translations.translation
-            FROM lines LEFT JOIN translations ON
-                lines.scriptid = translations.scriptid AND
-                lines.address = translations.address AND
-                translations.session = ?
-            WHERE lines.scriptid = ?
-            ORDER BY lines.address
-        ")?;
-
-        let mut rows = stmt.query((&self.session, script))?;
-        while let Some(row) = rows.next()? {
-            let (address, mut speaker, mut line, translation) = <(u32, String, String, Option<String>)>::try_from(row)?;
-            if speaker == "#Name[1]" {
-                "メアリ".clone_into(&mut speaker);
+    pub fn new(db: &Connection, settings: Settings) -> anyhow::Result<Self> {
+        Ok(Self { settings, characters: Registry::load(db)?, approximate: Cell::new(false), dropped: Cell::new(0) })
+    }
+
+    fn seen(&self, translated: &Translated) -> anyhow::Result<Seen> {
+        Ok(Seen {
+            speaker: if translated.speaker.is_empty() { None } else {
+                Some((translated.speaker.clone(), self.characters.decode(&translated.speaker)?.to_string()))
+            },
+            jpline: translated.line.clone(),
+            enline: translated.translation.clone()
+        })
+    }
+
+    /// Counts the tokens of the prompt, with the server's tokenizer if it has one.
+    async fn count_tokens(&self, cli: &Client, prompt: &str) -> anyhow::Result<usize> {
+        if !self.approximate.get() {
+            // the tokenize endpoint of llama.cpp and vLLM is outside of /v1
+            let root = self.settings.endpoint.trim_end_matches('/').trim_end_matches("/v1");
+            match tokenize(cli, &self.settings, format!("{root}/tokenize"), prompt).await {
+                Ok(tokens) => return Ok(tokens.len()),
+                // only a server without the endpoint gets an estimate; other errors would recur on completion anyway
+                Err(e) if e.downcast_ref::<reqwest::Error>().and_then(reqwest::Error::status).is_some_and(|s| matches!(s.as_u16(), 404 | 501)) => {
+                    eprintln!("no tokenize endpoint ({e}), counting tokens approximately");
+                    self.approximate.set(true);
+                },
+                Err(e) => return Err(e.context("counting tokens"))
             }
-            line = line.replace("#Name[1]", "メアリ");
-
-            if let Some(translation) = translation {
-                seen.push(Seen {
-                    speaker: if speaker.is_empty() { None } else { Some({
-                        let decoded = decode_jp_speaker(&speaker)?.to_string();
-                        (speaker, decoded)
-                    }) },
-                    jpline: line,
-                    enline: translation
-                });
+        }
+        Ok(approximate_tokens(prompt))
+    }
+
+    async fn complete_line(&self, cli: &Client, characters: &Registry, seen: &mut Vec<Seen>, speaker: &str, line: &str, speaker_prefix: &str) -> anyhow::Result<String> {
+        let settings = &self.settings;
+        loop {
+            let prompt = build_prompt(characters, seen, (!speaker.is_empty()).then_some(speaker), line)?;
+            // llama.cpp gets the tokens, so they are counted exactly
+            let tokens = match settings.api {
+                Api::Llamacpp => Some(tokenize(cli, settings, settings.url("tokenize"), &prompt).await?),
+                Api::Completions | Api::Chat => None
+            };
+            let len = match tokens {
+                Some(ref tokens) => tokens.len(),
+                None => self.count_tokens(cli, &prompt).await?
+            };
+            if len > settings.context - settings.max_tokens {
+                ensure!(!seen.is_empty(), "prompt for \"{line}\" takes {len} tokens without any context, more than the context ({}) leaves after max tokens ({})", settings.context, settings.max_tokens);
+                seen.remove(0);
                 continue;
             }
 
-            eprintln!("address = {address}");
-            let speaker_prefix = if speaker.is_empty() { speaker.clone() } else {
-                format!("[{}]: ", decode_jp_speaker(&speaker)?)
-            };
+            break match tokens {
+                Some(tokens) => get_completion(cli, settings, &tokens, speaker_prefix).await,
+                None => get_openai_completion(cli, settings, &prompt, speaker_prefix).await
+            }
+        }
+    }
+
+}
 
-            let translation = loop {
-                let prompt = loop {
-                    let prompt = build_prompt(&seen, (!speaker.is_empty()).then_some(&speaker), &line)?;
-                    let tokens = tokenize(&cli, &prompt).await?;
-                    if tokens.len() > 8192-48 {
-                        seen.remove(0);
-                        continue;
-                    }
-                    break tokens
-                };
-
-
-                match get_completion(&cli, &prompt, &speaker_prefix).await {
-                    Ok(tl) => break tl.strip_prefix(&speaker_prefix).unwrap().trim().to_owned(),
-                    Err(e) => return Err(e)
-                    /*Err(e) => match e.downcast::<MaxTokensReachedError>() {
-                        Ok(e) => {
-                            eprintln!("{e}");
-                            seen.remove(0);
-                            continue
-                        },
-                        Err(e) => return Err(e)
-                    }*/
-                }
+impl super::Translator for Translator {
+    fn session(&self) -> &str {
+        &self.settings.session
+    }
+
+    async fn translate(&self, cli: &Client, context: &[Translated], items: &[Pending]) -> anyhow::Result<Vec<Option<String>>> {
+        // complete_line drops the oldest lines once the prompt gets too long, and the ones from the context
+        // stay dropped; lines of this batch may not all make it into the context, so they are counted anew
+        let mut seen = context[self.dropped.get()..].iter().map(|t| self.seen(t)).collect::<anyhow::Result<Vec<_>>>()?;
+        let mut from_context = seen.len();
+        let mut translations = Vec::new();
+        for item in items {
+            match item.kind {
+                Kind::Line => eprintln!("address = {}", item.address),
+                Kind::Choice => eprintln!("choice address = {}", item.address),
+                Kind::String => eprintln!("string address = {}.{}", item.address, item.param.unwrap_or_default())
+            }
+            let speaker_prefix = if item.speaker.is_empty() { String::new() } else {
+                format!("[{}]: ", self.characters.decode(&item.speaker)?)
             };
-            
+
+            let before = seen.len();
+            let translation = self.complete_line(cli, &self.characters, &mut seen, &item.speaker, &item.text, &speaker_prefix).await?;
+            let dropped = (before - seen.len()).min(from_context);
+            from_context -= dropped;
+            self.dropped.set(self.dropped.get() + dropped);
             eprintln!("{speaker_prefix}{translation}\n");
-            tx.execute("
-                INSERT INTO translations(session, scriptid, address, translation)
-                VALUES (?, ?, ?, ?)
-            ", (&self.session, script, address, &translation))?;
-            seen.push(Seen {
-                speaker: if speaker.is_empty() { None } else { Some({
-                    let decoded = decode_jp_speaker(&speaker)?.to_string();
-                    (speaker, decoded)
-                }) },
-                jpline: line,
-                enline: translation
-            });
+            if item.kind == Kind::Line {
+                seen.push(self.seen(&Translated { speaker: item.speaker.clone(), line: item.text.clone(), translation: translation.clone() })?);
+            }
+            translations.push(Some(translation));
         }
-        drop(rows);
-        drop(stmt);
-        tx.commit()?;
-
-        Ok(())
+        Ok(translations)
     }
 }
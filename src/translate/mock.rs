@@ -0,0 +1,48 @@
+use reqwest::Client;
+use rusqlite::Connection;
+
+use crate::markup::Markup;
+
+use super::{Kind, Pending, Translated};
+
+/// Made-up english that only depends on the item, so the pipeline can be run without a server.
+/// Placeholders like the player's name are kept, so the markup survives the round trip.
+#[derive(Clone, Debug)]
+pub struct Translator {
+    // japanese and english text of each placeholder
+    placeholders: Vec<(String, String)>
+}
+
+impl Translator {
+    pub fn new(db: &Connection) -> anyhow::Result<Self> {
+        Ok(Self {
+            placeholders: Markup::load(db)?.placeholders().map(|(_, jp, en)| (jp.to_owned(), en.to_owned())).collect()
+        })
+    }
+}
+
+impl super::Translator for Translator {
+    fn session(&self) -> &str {
+        "mock"
+    }
+
+    fn batch_size(&self) -> usize {
+        16
+    }
+
+    async fn translate(&self, _client: &Client, _context: &[Translated], items: &[Pending]) -> anyhow::Result<Vec<Option<String>>> {
+        Ok(items.iter().map(|item| {
+            let mut translation = match item.kind {
+                Kind::Line => format!("Line {} has {} characters.", item.address, item.text.chars().count()),
+                Kind::Choice => format!("Choice {}", item.address),
+                Kind::String => format!("String {}.{}", item.address, item.param.unwrap_or_default())
+            };
+            for (jp, en) in self.placeholders.iter() {
+                for _ in item.text.matches(jp.as_str()) {
+                    translation = format!("{en}: {translation}");
+                }
+            }
+            Some(translation)
+        }).collect())
+    }
+}
@@ -1,41 +1,228 @@
 mod google;
 mod llm;
+mod mock;
 
 use anyhow::Context as _;
 use clap::{Parser, ValueEnum};
 use rusqlite::Connection;
 use reqwest::Client;
 
+use crate::{config, markup::{Lang, Markup}, scene};
 use google::Translator as GoogleTranslator;
-use llm::Translator as LlmTramslator;
+use llm::{Overrides as LlmOverrides, Settings as LlmSettings, Translator as LlmTranslator};
+use mock::Translator as MockTranslator;
 
 #[derive(Clone, Copy, Debug, ValueEnum)]
 enum Provider {
     Google,
-    Llm
+    Llm,
+    /// deterministic translations without a server, for testing
+    Mock
 }
 
 #[derive(Parser)]
 pub struct Args {
     provider: Provider,
-    script_id: u32
+    script_id: u32,
+    #[command(flatten)]
+    llm: LlmOverrides
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Line,
+    Choice,
+    String
+}
+
+/// Text that has no translation in the session yet, with its markup protected.
+#[derive(Clone, Debug)]
+pub struct Pending {
+    pub kind: Kind,
+    pub address: u32,
+    // only strings have one
+    pub param: Option<u32>,
+    // empty for narration, choices and strings
+    pub speaker: String,
+    pub text: String
+}
+
+/// A line that was translated before, with its markup protected.
+#[derive(Clone, Debug)]
+pub struct Translated {
+    pub speaker: String,
+    pub line: String,
+    pub translation: String
+}
+
+/// A translation provider. Finding the text to translate and storing the results is left to
+/// [`translate`], so a provider only has to turn japanese into english.
+pub trait Translator {
+    /// Session the translations are stored in.
+    fn session(&self) -> &str;
+
+    /// Most items to translate in one call.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Translates the items in order, or gives `None` for ones that could not be translated. `context`
+    /// holds the lines before the items, and only grows at the end from one call to the next.
+    async fn translate(&self, client: &Client, context: &[Translated], items: &[Pending]) -> anyhow::Result<Vec<Option<String>>>;
 }
 
 pub async fn run(mut db: Connection, args: Args) -> anyhow::Result<()> {
     let cli = Client::new();
 
+    if let Some(scene) = scene::get(&db, args.script_id)? {
+        println!("scene {scene}");
+    }
+
     match args.provider {
         Provider::Google => {
-            let tl = GoogleTranslator::new(db.query_row(
-                "SELECT value FROM config WHERE name = 'google_api_key'",
-                (),
-                |row| row.get(0)
-            ).context("no google_api_key configured")?);
-            tl.translate(cli, &mut db, args.script_id).await?;
+            let tl = GoogleTranslator::new(
+                db.query_row(
+                    "SELECT value FROM config WHERE name = 'google_api_key'",
+                    (),
+                    |row| row.get(0)
+                ).context("no google_api_key configured")?,
+                config::get(&db, "google_endpoint")?.unwrap_or_else(|| "https://translation.googleapis.com".to_owned())
+            );
+            translate(&tl, &cli, &mut db, args.script_id).await?;
         },
         Provider::Llm => {
-            let tl = LlmTramslator::new("vntl-greedy-20240823".to_owned())?;
-            tl.translate(cli, &mut db, args.script_id).await?;
+            let settings = LlmSettings::load(&db, args.llm)?;
+            println!("session {} at {}", settings.session, settings.endpoint);
+            let tl = LlmTranslator::new(&db, settings)?;
+            translate(&tl, &cli, &mut db, args.script_id).await?;
+        },
+        Provider::Mock => translate(&MockTranslator::new(&db)?, &cli, &mut db, args.script_id).await?
+    }
+
+    Ok(())
+}
+
+/// Translated lines of the scene before `script`, to start the context with.
+fn predecessor_context(db: &Connection, markup: &Markup, session: &str, script: u32) -> anyhow::Result<Vec<Translated>> {
+    let Some(predecessor) = scene::get(db, script)?.and_then(|s| s.predecessor) else { return Ok(Vec::new()) };
+
+    let context = db.prepare_cached("
+        SELECT lines.speaker, lines.line, translations.translation
+        FROM lines JOIN translations USING (scriptid, address)
+        WHERE translations.session = ? AND lines.scriptid = ?
+        ORDER BY lines.address
+    ")?.query_map((session, predecessor), |row| row.try_into())?
+        .map(|row| {
+            let (speaker, line, translation): (String, String, String) = row?;
+            Ok(Translated {
+                speaker: markup.protect(&speaker, Lang::Ja).into_owned(),
+                line: markup.protect(&line, Lang::Ja).into_owned(),
+                translation: markup.protect(&translation, Lang::En).into_owned()
+            })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    eprintln!("context starts with {} lines from {predecessor}", context.len());
+    Ok(context)
+}
+
+/// Translates the items and stores the results, adding translated lines to the context.
+async fn translate_batch(tl: &impl Translator, client: &Client, db: &mut Connection, markup: &Markup, script: u32, context: &mut Vec<Translated>, items: &[Pending]) -> anyhow::Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let translations = tl.translate(client, context, items).await?;
+
+    let tx = db.transaction()?;
+    for (item, translation) in items.iter().zip(translations) {
+        // make insertions resilient; try to salvage as much data as possible
+        let Some(translation) = translation else {
+            eprintln!("warning: script {script} addr {} has no translation", item.address);
+            continue;
+        };
+        let insert = match item.kind {
+            Kind::Line => "INSERT INTO translations(session, scriptid, address, translation) VALUES (?1, ?2, ?3, ?5)",
+            Kind::Choice => "INSERT INTO choicetranslations(session, scriptid, address, translation) VALUES (?1, ?2, ?3, ?5)",
+            Kind::String => "INSERT INTO stringtranslations(session, scriptid, address, param, translation) VALUES (?1, ?2, ?3, ?4, ?5)"
+        };
+        if let Err(e) = tx.execute(insert, (tl.session(), script, item.address, item.param, markup.restore(&translation))) {
+            eprintln!("warning: script {script} addr {} failed to save", item.address);
+            eprintln!("tl: {translation}");
+            eprintln!("error: {e}");
+            continue;
+        }
+        if item.kind == Kind::Line {
+            context.push(Translated { speaker: item.speaker.clone(), line: item.text.clone(), translation });
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Translates everything in the script that has no translation in the provider's session yet.
+pub async fn translate(tl: &impl Translator, client: &Client, db: &mut Connection, script: u32) -> anyhow::Result<()> {
+    let markup = Markup::load(db)?;
+    let session = tl.session().to_owned();
+    let mut context = predecessor_context(db, &markup, &session, script)?;
+
+    // lines go in order, with the ones translated before as context for the rest
+    let lines = db.prepare_cached("
+        SELECT lines.address, lines.speaker, lines.line, translations.translation
+        FROM lines LEFT JOIN translations ON
+            lines.scriptid = translations.scriptid AND
+            lines.address = translations.address AND
+            translations.session = ?
+        WHERE lines.scriptid = ?
+        ORDER BY lines.address
+    ")?.query_map((&session, script), |row| row.try_into())?.collect::<Result<Vec<(u32, String, String, Option<String>)>, _>>()?;
+
+    let mut batch = Vec::new();
+    for (address, speaker, line, translation) in lines {
+        let speaker = markup.protect(&speaker, Lang::Ja).into_owned();
+        let line = markup.protect(&line, Lang::Ja).into_owned();
+        match translation {
+            Some(translation) => {
+                translate_batch(tl, client, db, &markup, script, &mut context, &batch).await?;
+                batch.clear();
+                context.push(Translated { speaker, line, translation: markup.protect(&translation, Lang::En).into_owned() });
+            },
+            None => {
+                batch.push(Pending { kind: Kind::Line, address, param: None, speaker, text: line });
+                if batch.len() >= tl.batch_size() {
+                    translate_batch(tl, client, db, &markup, script, &mut context, &batch).await?;
+                    batch.clear();
+                }
+            }
+        }
+    }
+    translate_batch(tl, client, db, &markup, script, &mut context, &batch).await?;
+
+    // choices and strings go last, with the whole script as context
+    let choices = db.prepare_cached("
+        SELECT address, NULL, option
+        FROM choices
+        WHERE scriptid = ?2
+            AND (?1, ?2, address) NOT IN
+                (SELECT session, scriptid, address FROM choicetranslations)
+        ORDER BY address
+    ")?.query_map((&session, script), |row| row.try_into())?.collect::<Result<Vec<(u32, Option<u32>, String)>, _>>()?;
+    let strings = db.prepare_cached("
+        SELECT address, param, text
+        FROM strings
+        WHERE scriptid = ?2
+            -- only params registered as displayed text, not file names or labels
+            AND (opcode, call, param) IN (SELECT opcode, call, param FROM stringparams)
+            AND (?1, ?2, address, param) NOT IN
+                (SELECT session, scriptid, address, param FROM stringtranslations)
+        ORDER BY address, param
+    ")?.query_map((&session, script), |row| row.try_into())?.collect::<Result<Vec<(u32, Option<u32>, String)>, _>>()?;
+
+    for (kind, rows) in [(Kind::Choice, choices), (Kind::String, strings)] {
+        let pending = rows.into_iter()
+            .map(|(address, param, text)| Pending { kind, address, param, speaker: String::new(), text: markup.protect(&text, Lang::Ja).into_owned() })
+            .collect::<Vec<_>>();
+        for chunk in pending.chunks(tl.batch_size()) {
+            translate_batch(tl, client, db, &markup, script, &mut context, chunk).await?;
         }
     }
 
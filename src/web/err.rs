@@ -1,3 +1,5 @@
+#![allow(clippy::result_large_err)]
+
 use std::{convert::Infallible, fmt::Display};
 use axum::http::StatusCode;
 
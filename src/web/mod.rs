@@ -4,8 +4,8 @@ mod model;
 mod view;
 
 use err::ResultExt as _;
-use model::{Model, Row};
-use view::View;
+use model::{ChoiceRow, Model, Row};
+use view::{Kind, View};
 
 use std::sync::Arc;
 
@@ -34,6 +34,8 @@ pub async fn run(db: Connection, args: Args) -> anyhow::Result<()> {
             .route("/:session/:scriptid", get(table))
             .route("/:session/:scriptid/:address", on(MethodFilter::GET.or(MethodFilter::PUT), table_row))
             .route("/:session/:scriptid/:address/edit", get(table_row_editor))
+            .route("/:session/:scriptid/choices/:address", on(MethodFilter::GET.or(MethodFilter::PUT), choice_row))
+            .route("/:session/:scriptid/choices/:address/edit", get(choice_row_editor))
             .layer(middleware::map_response(|mut r: Response<_>| async {
                 r.headers_mut().append("cache-control", "no-cache".parse().unwrap());
                 r
@@ -42,10 +44,15 @@ pub async fn run(db: Connection, args: Args) -> anyhow::Result<()> {
             .with_state(Arc::new(AppState {
                 model: Model::new(db),
                 view: View::new(
-                    |session, scriptid, address|
-                        format!("/{session}/{scriptid}/{address}"),
-                    |session, scriptid, address|
-                        format!("/{session}/{scriptid}/{address}/edit")
+                    |session, scriptid| format!("/{session}/{scriptid}"),
+                    |kind, session, scriptid, address| match kind {
+                        Kind::Line => format!("/{session}/{scriptid}/{address}"),
+                        Kind::Choice => format!("/{session}/{scriptid}/choices/{address}")
+                    },
+                    |kind, session, scriptid, address| match kind {
+                        Kind::Line => format!("/{session}/{scriptid}/{address}/edit"),
+                        Kind::Choice => format!("/{session}/{scriptid}/choices/{address}/edit")
+                    }
                 )
             }))
     ).await?)
@@ -62,7 +69,9 @@ async fn table(
     Path(ShowTableParams { session, scriptid }): Path<ShowTableParams>
 ) -> axum::response::Result<impl IntoResponse> {
     let rows = state.model.translations(&session, scriptid).with_ise()?;
-    let res = state.view.render(&session, scriptid, rows).to_string();
+    let choices = state.model.choices(&session, scriptid).with_ise()?;
+    let scene = state.model.scene(scriptid).with_ise()?;
+    let res = state.view.render(&session, scriptid, scene, rows, choices).to_string();
 
     Ok(Html(res))
 }
@@ -88,7 +97,7 @@ async fn table_row(
         &session, scriptid, address,
         frm.as_ref().map(|f| f.0.current.as_str())
     ).with_ise()?;
-    let res = state.view.render_current(&session, scriptid, address, current).to_string();
+    let res = state.view.render_current(Kind::Line, &session, scriptid, address, current).to_string();
 
     Ok(Html(res))
 }
@@ -98,7 +107,31 @@ async fn table_row_editor(
     Path(TableRowParams { session, scriptid, address }): Path<TableRowParams>
 ) -> axum::response::Result<impl IntoResponse> {
     let current = state.model.translation(&session, scriptid, address, None).with_ise()?;
-    let res = state.view.render_current_edit(&session, scriptid, address, current).to_string();
+    let res = state.view.render_current_edit(Kind::Line, &session, scriptid, address, current).to_string();
 
     Ok(Html(res))
-}
\ No newline at end of file
+}
+
+async fn choice_row(
+    State(state): State<Arc<AppState>>,
+    Path(TableRowParams { session, scriptid, address }): Path<TableRowParams>,
+    frm: Option<Form<TableRowQuery>>
+) -> axum::response::Result<impl IntoResponse> {
+    let current = state.model.choice_translation(
+        &session, scriptid, address,
+        frm.as_ref().map(|f| f.0.current.as_str())
+    ).with_ise()?;
+    let res = state.view.render_current(Kind::Choice, &session, scriptid, address, current).to_string();
+
+    Ok(Html(res))
+}
+
+async fn choice_row_editor(
+    State(state): State<Arc<AppState>>,
+    Path(TableRowParams { session, scriptid, address }): Path<TableRowParams>
+) -> axum::response::Result<impl IntoResponse> {
+    let current = state.model.choice_translation(&session, scriptid, address, None).with_ise()?;
+    let res = state.view.render_current_edit(Kind::Choice, &session, scriptid, address, current).to_string();
+
+    Ok(Html(res))
+}
@@ -2,6 +2,8 @@ use std::sync::Mutex;
 
 use rusqlite::{Connection, OptionalExtension as _};
 
+use crate::{characters::Registry, scene::{self, Scene}};
+
 pub struct Model {
     db: Mutex<Connection>
 }
@@ -10,9 +12,16 @@ pub struct Model {
 pub struct Row {
     pub address: u32,
     pub speaker: String,
+    // from the character registry
+    pub enspeaker: Option<String>,
+    pub voice: Option<u32>,
+    // widths of the original lines, separated by spaces
+    pub layout: Option<String>,
     pub original: String,
     pub control: String,
-    pub current: String
+    pub current: String,
+    // the original line the current translation was made from, if it has changed since
+    pub stale: Option<String>
 }
 
 impl TryFrom<&rusqlite::Row<'_>> for Row {
@@ -22,9 +31,38 @@ impl TryFrom<&rusqlite::Row<'_>> for Row {
         Ok(Self {
             address: row.get(0)?,
             speaker: row.get(1)?,
-            original: row.get(2)?,
-            control: row.get(3)?,
-            current: row.get(4)?
+            enspeaker: None,
+            voice: row.get(2)?,
+            layout: row.get(3)?,
+            original: row.get(4)?,
+            control: row.get(5)?,
+            current: row.get(6)?,
+            stale: row.get(7)?
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChoiceRow {
+    pub address: u32,
+    pub prompt: String,
+    pub choice: u32,
+    pub original: String,
+    pub control: String,
+    pub current: String
+}
+
+impl TryFrom<&rusqlite::Row<'_>> for ChoiceRow {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &rusqlite::Row<'_>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            address: row.get(0)?,
+            prompt: row.get(1)?,
+            choice: row.get(2)?,
+            original: row.get(3)?,
+            control: row.get(4)?,
+            current: row.get(5)?
         })
     }
 }
@@ -34,22 +72,33 @@ impl Model {
         Self { db: Mutex::new(db) }
     }
 
-    pub fn translations(&self, session: &str, scriptid: u32) -> rusqlite::Result<Vec<Row>> {
+    pub fn translations(&self, session: &str, scriptid: u32) -> anyhow::Result<Vec<Row>> {
         let db = self.db.lock().unwrap();
 
         let mut stmt = db.prepare_cached("
-            SELECT lines.address, lines.speaker, lines.line, google.translation, IFNULL(current.translation, '') FROM lines
+            SELECT lines.address, lines.speaker, voices.voice, layouts.widths, lines.line, IFNULL(google.translation, ''), IFNULL(current.translation, ''), stale.oldline FROM lines
+            LEFT JOIN voices USING (scriptid, address)
+            LEFT JOIN layouts USING (scriptid, address)
             LEFT JOIN translations AS google
                 ON google.session = 'google' AND google.scriptid = lines.scriptid AND google.address = lines.address
             LEFT JOIN translations AS current
-                ON current.session = ? AND current.scriptid = lines.scriptid AND current.address = lines.address
-            WHERE lines.scriptid = ?
+                ON current.session = ?1 AND current.scriptid = lines.scriptid AND current.address = lines.address
+            LEFT JOIN staletranslations AS stale
+                ON stale.session = ?1 AND stale.scriptid = lines.scriptid AND stale.address = lines.address
+            WHERE lines.scriptid = ?2
             ORDER BY lines.address
         ")?;
 
-        let rows = stmt
+        let mut rows = stmt
             .query_map((&session, scriptid), |row| Row::try_from(row))?
-            .collect::<rusqlite::Result<_>>()?;
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let characters = Registry::load(&db)?;
+        for row in rows.iter_mut() {
+            if !row.speaker.is_empty() {
+                row.enspeaker = characters.decode(&row.speaker).ok().map(|s| s.to_string());
+            }
+        }
 
         Ok(rows)
     }
@@ -71,4 +120,47 @@ impl Model {
             ).optional().map(Option::unwrap_or_default)
         }
     }
+
+    pub fn choices(&self, session: &str, scriptid: u32) -> rusqlite::Result<Vec<ChoiceRow>> {
+        let db = self.db.lock().unwrap();
+
+        let mut stmt = db.prepare_cached("
+            SELECT choices.address, choices.prompt, choices.choice, choices.option, IFNULL(google.translation, ''), IFNULL(current.translation, '') FROM choices
+            LEFT JOIN choicetranslations AS google
+                ON google.session = 'google' AND google.scriptid = choices.scriptid AND google.address = choices.address
+            LEFT JOIN choicetranslations AS current
+                ON current.session = ? AND current.scriptid = choices.scriptid AND current.address = choices.address
+            WHERE choices.scriptid = ?
+            ORDER BY choices.address
+        ")?;
+
+        let rows = stmt
+            .query_map((&session, scriptid), |row| ChoiceRow::try_from(row))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(rows)
+    }
+
+    pub fn choice_translation(&self, session: &str, scriptid: u32, address: u32, update: Option<&str>) -> rusqlite::Result<String> {
+        let db = self.db.lock().unwrap();
+
+        if let Some(translation) = update {
+            db.query_row(
+                "INSERT OR REPLACE INTO choicetranslations(session, scriptid, address, translation) VALUES (?, ?, ?, TRIM(?)) RETURNING translation",
+                (session, scriptid, address, translation),
+                |row| row.get(0)
+            )
+        } else {
+            db.query_row(
+                "SELECT translation FROM choicetranslations WHERE session = ? AND scriptid = ? AND address = ?",
+                (session, scriptid, address),
+                |row| row.get(0)
+            ).optional().map(Option::unwrap_or_default)
+        }
+    }
+
+    pub fn scene(&self, scriptid: u32) -> rusqlite::Result<Option<Scene>> {
+        let db = self.db.lock().unwrap();
+        scene::get(&db, scriptid)
+    }
 }
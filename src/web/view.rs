@@ -1,10 +1,19 @@
 use std::fmt::Display;
 use html::{scripting::Script, tables::{children::TableRowChild, TableCell, TableRow}};
-use super::Row;
+use crate::scene::Scene;
+use super::{ChoiceRow, Row};
 
-pub type CurrentUrlGenerator = Box<dyn Fn(&str, u32, u32) -> String + Send + Sync>;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Line,
+    Choice
+}
+
+pub type CurrentUrlGenerator = Box<dyn Fn(Kind, &str, u32, u32) -> String + Send + Sync>;
+pub type TableUrlGenerator = Box<dyn Fn(&str, u32) -> String + Send + Sync>;
 
 pub struct View {
+    table: TableUrlGenerator,
     current: CurrentUrlGenerator,
     edit_current: CurrentUrlGenerator
 }
@@ -20,14 +29,16 @@ fn htmx() -> Script {
 
 impl View {
     pub fn new(
-        current: impl Fn(&str, u32, u32) -> String + Send + Sync + 'static,
-        edit_current: impl Fn(&str, u32, u32) -> String + Send + Sync + 'static
+        table: impl Fn(&str, u32) -> String + Send + Sync + 'static,
+        current: impl Fn(Kind, &str, u32, u32) -> String + Send + Sync + 'static,
+        edit_current: impl Fn(Kind, &str, u32, u32) -> String + Send + Sync + 'static
     ) -> Self {
-        Self { current: Box::new(current), edit_current: Box::new(edit_current) }
+        Self { table: Box::new(table), current: Box::new(current), edit_current: Box::new(edit_current) }
     }
 
     pub fn render_current(
         &self,
+        kind: Kind,
         session: &str,
         scriptid: u32,
         address: u32,
@@ -38,19 +49,20 @@ impl View {
                 .class("current")
                 .division(|b| b.text(current))
                 .button(|b| b
-                    .data("hx-get", (self.edit_current)(session, scriptid, address))
+                    .data("hx-get", (self.edit_current)(kind, session, scriptid, address))
                     .text("✏️")))
             .build()
     }
 
     pub fn render_current_edit(
         &self,
+        kind: Kind,
         session: &str,
         scriptid: u32,
         address: u32,
         current: String
     ) -> impl Display + Into<TableRowChild> {
-        let url = (self.current)(session, scriptid, address);
+        let url = (self.current)(kind, session, scriptid, address);
 
         TableCell::builder()
             .division(|b| b
@@ -70,7 +82,9 @@ impl View {
         &self,
         session: &str,
         scriptid: u32,
-        rows: impl IntoIterator<Item = Row>
+        scene: Option<Scene>,
+        rows: impl IntoIterator<Item = Row>,
+        choices: impl IntoIterator<Item = ChoiceRow>
     ) -> impl Display {
         html::root::Html::builder()
             .lang("en")
@@ -79,6 +93,22 @@ impl View {
                 .title(|b| b.text("Bruh"))
                 .style(|b| b.text(include_str!("view.css"))))
             .body(|b| b
+                .navigation(|b| {
+                    let scene = scene.unwrap_or(Scene { scriptid, ..Default::default() });
+                    if let Some(predecessor) = scene.predecessor {
+                        b.anchor(|b| b.href((self.table)(session, predecessor)).text(format!("← {predecessor}")));
+                    }
+                    b.span(|b| b.class("scene").text(match (scene.title, scene.route) {
+                        (Some(title), Some(route)) => format!("{scriptid}: {title} (route {route})"),
+                        (Some(title), None) => format!("{scriptid}: {title}"),
+                        (None, Some(route)) => format!("{scriptid} (route {route})"),
+                        (None, None) => scriptid.to_string()
+                    }));
+                    if let Some(successor) = scene.successor {
+                        b.anchor(|b| b.href((self.table)(session, successor)).text(format!("{successor} →")));
+                    }
+                    b
+                })
                 .table(|b| b
                     .table_column_group(|b| b
                         .table_column(|b| b
@@ -97,13 +127,63 @@ impl View {
                     .table_body(|b| b
                         .data("hx-target", "closest td")
                         .data("hx-swap", "outerHTML")
-                        .extend(rows.into_iter().map(|Row { address, speaker, original, control, current }|
+                        .extend(rows.into_iter().map(|Row { address, speaker, enspeaker, voice, layout, original, control, current, stale }|
+                            TableRow::builder()
+                                .table_cell(|b| {
+                                    b.text(address.to_string());
+                                    if let Some(voice) = voice {
+                                        b.division(|b| b.class("voice").text(format!("voice {voice:X}")));
+                                    }
+                                    if let Some(layout) = layout {
+                                        let n = layout.split_whitespace().count();
+                                        b.division(|b| b.class("layout").title(format!("widths: {layout}")).text(format!("{n} lines")));
+                                    }
+                                    b
+                                })
+                                .table_cell(|b| {
+                                    b.lang("ja").text(speaker);
+                                    if let Some(enspeaker) = enspeaker {
+                                        b.division(|b| b.class("speaker").lang("en").text(enspeaker));
+                                    }
+                                    b
+                                })
+                                .table_cell(|b| {
+                                    b.lang("ja").text(original);
+                                    if let Some(stale) = stale {
+                                        b.division(|b| b.class("stale").text(format!("changed since translated from: {stale}")));
+                                    }
+                                    b
+                                })
+                                .table_cell(|b| b.text(control))
+                                .push(self.render_current(Kind::Line, session, scriptid, address, current))
+                            .build()))))
+                .table(|b| b
+                    .table_column_group(|b| b
+                        .table_column(|b| b
+                            .span("2")
+                            .class("meta-cols"))
+                        .table_column(|b| b
+                            .span("3")
+                            .class("text-cols")))
+                    .table_head(|b| b
+                        .table_row(|b| b
+                            .table_header(|b| b.text("address"))
+                            .table_header(|b| b.text("choice"))
+                            .table_header(|b| b.text("original"))
+                            .table_header(|b| b.text("control"))
+                            .table_header(|b| b.text("current"))))
+                    .table_body(|b| b
+                        .data("hx-target", "closest td")
+                        .data("hx-swap", "outerHTML")
+                        .extend(choices.into_iter().map(|ChoiceRow { address, prompt, choice, original, control, current }|
                             TableRow::builder()
                                 .table_cell(|b| b.text(address.to_string()))
-                                .table_cell(|b| b.lang("ja").text(speaker))
+                                .table_cell(|b| b
+                                    .text(format!("{:X}", choice & !0xFF000000))
+                                    .division(|b| b.class("prompt").lang("ja").text(prompt)))
                                 .table_cell(|b| b.lang("ja").text(original))
                                 .table_cell(|b| b.text(control))
-                                .push(self.render_current(session, scriptid, address, current))
+                                .push(self.render_current(Kind::Choice, session, scriptid, address, current))
                             .build()))))
                 .push(htmx()))
             .build()
@@ -0,0 +1,24 @@
+//! Runs `blume cleanup` on speakers from older translations.
+
+mod common;
+
+use rusqlite::Connection;
+
+use common::{blume, stderr, Db};
+
+#[test]
+fn cleanup_knows_old_spellings() {
+    let db = Db::init("cleanup");
+    let conn = Connection::open(&db).unwrap();
+    conn.execute("INSERT INTO scripts(id, script) VALUES (100, x'')", ()).unwrap();
+    for (address, speaker) in [(1, "Klaus"), (2, "Ilia's voice"), (3, "Relm"), (4, "Claus"), (5, "Narrator")] {
+        conn.execute("INSERT INTO lines(scriptid, address, speaker, line) VALUES (100, ?, ?, '')", (address, speaker)).unwrap();
+    }
+
+    let out = blume(&db, &["cleanup", "100"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let speakers = conn.prepare("SELECT speaker FROM lines ORDER BY address").unwrap()
+        .query_map((), |row| row.get(0)).unwrap()
+        .collect::<Result<Vec<String>, _>>().unwrap();
+    assert_eq!(speakers, ["クラウス", "イリヤの声", "レルム", "クラウス", ""]);
+}
@@ -0,0 +1,53 @@
+//! Running the `blume` binary on a throwaway database.
+
+use std::{ops::Deref, path::{Path, PathBuf}, process::{Command, Output}};
+
+/// A database file that is removed when the test ends, whether or not it passed.
+pub struct Db(PathBuf);
+
+impl Db {
+    /// Makes an empty database with `blume init`.
+    pub fn init(name: &str) -> Self {
+        let db = Self(std::env::temp_dir().join(format!("blume-test-{name}-{}.db", std::process::id())));
+        let _ = std::fs::remove_file(&db);
+        let out = blume(&db, &["init"]);
+        assert!(out.status.success(), "init failed: {}", stderr(&out));
+        db
+    }
+}
+
+impl Deref for Db {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Db {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+pub fn blume(db: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_blume"))
+        .arg("-f").arg(db)
+        .args(args)
+        .output().unwrap()
+}
+
+#[allow(dead_code)] // not every test file reads stdout
+pub fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+pub fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
@@ -0,0 +1,179 @@
+//! A stand-in for the llama.cpp server, the openai-compatible apis and the Google translation api,
+//! answering with scripted replies, or made-up translations once the script runs out.
+
+use std::{collections::VecDeque, sync::{Arc, Mutex}, thread};
+
+use axum::{extract::State, http::{header::AUTHORIZATION, HeaderMap, StatusCode}, response::{IntoResponse, Response}, routing::post, Json, Router};
+use serde_json::{json, Value};
+
+/// What the server answers to one request.
+#[derive(Clone, Debug)]
+#[allow(dead_code)] // not every test file uses every reply
+pub enum Reply {
+    /// a completion, from llama.cpp or either openai api
+    Completion { content: String, truncated: bool },
+    /// google translations in order; `None` leaves out the translatedText
+    Google(Vec<Option<String>>),
+    /// a failure with this status
+    Status(u16)
+}
+
+#[derive(Default)]
+struct Script {
+    tokenize: VecDeque<Reply>,
+    completion: VecDeque<Reply>,
+    google: VecDeque<Reply>,
+    // path, authorization header and body of every request
+    requests: Vec<(String, Option<String>, Value)>
+}
+
+type Shared = Arc<Mutex<Script>>;
+
+#[derive(Clone)]
+pub struct Fake {
+    pub url: String,
+    script: Shared
+}
+
+#[allow(dead_code)]
+impl Fake {
+    /// Starts a server on a free port, which runs until the test ends.
+    pub fn start() -> Self {
+        let script = Shared::default();
+        let app = Router::new()
+            .route("/tokenize", post(tokenize))
+            .route("/completion", post(completion))
+            .route("/v1/completions", post(openai_completions))
+            .route("/v1/chat/completions", post(openai_chat))
+            .route("/language/translate/v2", post(google))
+            .with_state(script.clone());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("no free port");
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, app).await.unwrap();
+            })
+        });
+
+        Self { url, script }
+    }
+
+    pub fn tokenize(&self, reply: Reply) -> &Self {
+        self.script.lock().unwrap().tokenize.push_back(reply);
+        self
+    }
+
+    pub fn completion(&self, reply: Reply) -> &Self {
+        self.script.lock().unwrap().completion.push_back(reply);
+        self
+    }
+
+    pub fn google(&self, reply: Reply) -> &Self {
+        self.script.lock().unwrap().google.push_back(reply);
+        self
+    }
+
+    /// Bodies of the requests made to `path`, in order.
+    pub fn requests(&self, path: &str) -> Vec<Value> {
+        self.script.lock().unwrap().requests.iter().filter(|(p, ..)| p == path).map(|(.., body)| body.clone()).collect()
+    }
+
+    /// Authorization headers of the requests made to `path`, in order.
+    pub fn authorizations(&self, path: &str) -> Vec<Option<String>> {
+        self.script.lock().unwrap().requests.iter().filter(|(p, ..)| p == path).map(|(_, auth, _)| auth.clone()).collect()
+    }
+}
+
+fn status(code: u16) -> Response {
+    (StatusCode::from_u16(code).unwrap(), format!("scripted failure {code}")).into_response()
+}
+
+fn record(script: &mut Script, path: &str, headers: &HeaderMap, body: &Value) {
+    let auth = headers.get(AUTHORIZATION).and_then(|h| h.to_str().ok()).map(str::to_owned);
+    script.requests.push((path.to_owned(), auth, body.clone()));
+}
+
+async fn tokenize(State(script): State<Shared>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let mut script = script.lock().unwrap();
+    record(&mut script, "/tokenize", &headers, &body);
+    match script.tokenize.pop_front() {
+        Some(Reply::Status(code)) => status(code),
+        Some(reply) => panic!("not a tokenize reply: {reply:?}"),
+        // a token per character is close enough
+        None => {
+            let content = body["content"].as_str().unwrap_or_default();
+            Json(json!({ "tokens": content.chars().map(|c| c as u32).collect::<Vec<_>>() })).into_response()
+        }
+    }
+}
+
+async fn completion(State(script): State<Shared>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let mut script = script.lock().unwrap();
+    record(&mut script, "/completion", &headers, &body);
+    match script.completion.pop_front() {
+        Some(Reply::Completion { content, truncated }) => Json(json!({ "content": content, "truncated": truncated })).into_response(),
+        Some(Reply::Status(code)) => status(code),
+        Some(reply) => panic!("not a completion reply: {reply:?}"),
+        // honour the speaker the grammar asks for, like llama.cpp would
+        None => {
+            let speaker = body["grammar"].as_str()
+                .and_then(|g| g.split('"').nth(1))
+                .unwrap_or_default();
+            Json(json!({ "content": format!("{speaker}Translated."), "truncated": false })).into_response()
+        }
+    }
+}
+
+// the prompt already ends with the speaker, so the made-up text goes without one
+async fn openai_completions(State(script): State<Shared>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let mut script = script.lock().unwrap();
+    record(&mut script, "/v1/completions", &headers, &body);
+    match script.completion.pop_front() {
+        Some(Reply::Completion { content, truncated }) => openai_choice(json!({ "text": content }), truncated),
+        Some(Reply::Status(code)) => status(code),
+        Some(reply) => panic!("not a completion reply: {reply:?}"),
+        None => openai_choice(json!({ "text": "Translated." }), false)
+    }
+}
+
+// honour the speaker the system message asks to start with
+async fn openai_chat(State(script): State<Shared>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let mut script = script.lock().unwrap();
+    record(&mut script, "/v1/chat/completions", &headers, &body);
+    let message = |content: String| json!({ "message": { "role": "assistant", "content": content } });
+    match script.completion.pop_front() {
+        Some(Reply::Completion { content, truncated }) => openai_choice(message(content), truncated),
+        Some(Reply::Status(code)) => status(code),
+        Some(reply) => panic!("not a completion reply: {reply:?}"),
+        None => {
+            let speaker = body.pointer("/messages/0/content").and_then(Value::as_str)
+                .and_then(|s| s.split('`').nth(1))
+                .unwrap_or_default();
+            openai_choice(message(format!("{speaker}Translated.")), false)
+        }
+    }
+}
+
+fn openai_choice(mut choice: Value, truncated: bool) -> Response {
+    choice["index"] = json!(0);
+    choice["finish_reason"] = json!(if truncated { "length" } else { "stop" });
+    Json(json!({ "choices": [choice] })).into_response()
+}
+
+async fn google(State(script): State<Shared>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+    let mut script = script.lock().unwrap();
+    record(&mut script, "/language/translate/v2", &headers, &body);
+    let translations = match script.google.pop_front() {
+        Some(Reply::Google(translations)) => translations,
+        Some(Reply::Status(code)) => return status(code),
+        Some(reply) => panic!("not a google reply: {reply:?}"),
+        None => vec![Some("Translated.".to_owned()); body["q"].as_array().map_or(0, Vec::len)]
+    };
+    let translations = translations.into_iter()
+        .map(|t| t.map_or_else(|| json!({}), |t| json!({ "translatedText": t })))
+        .collect::<Vec<_>>();
+    Json(json!({ "data": { "translations": translations } })).into_response()
+}
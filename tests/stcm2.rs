@@ -0,0 +1,129 @@
+//! Runs `blume stcm2` on small scripts made up here.
+
+mod common;
+
+use rusqlite::Connection;
+
+use common::{blume, stderr, stdout, Db};
+
+const OP_LINE: u32 = 0xd2;
+const OP_YIELD: u32 = 0xd3;
+
+fn put(out: &mut Vec<u8>, values: &[u32]) {
+    out.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+}
+
+/// A script of actions that each show a Shift_JIS string, or take no parameters.
+fn script(actions: &[(u32, Option<&[u8]>)]) -> Vec<u8> {
+    // after the header, the empty global data and the code start magic
+    const CODE_START: usize = 32 + 48 + 16 + 12;
+    let mut code = Vec::new();
+    for &(opcode, text) in actions {
+        let addr = CODE_START + code.len();
+        let mut data = Vec::new();
+        if let Some(text) = text {
+            let qlen = text.len() / 4 + 1;
+            put(&mut data, &[0, qlen as u32, 1, 4*qlen as u32]);
+            data.extend(text);
+            data.resize(16 + 4*qlen, 0);
+        }
+        let nparams = usize::from(text.is_some());
+        put(&mut code, &[0, opcode, nparams as u32, (16 + 12*nparams + data.len()) as u32]);
+        if text.is_some() {
+            put(&mut code, &[(addr + 16 + 12) as u32, 0xff000000, 0xff000000]);
+        }
+        code.extend(data);
+    }
+
+    let mut file = b"STCM2".to_vec();
+    file.resize(32, 0);
+    put(&mut file, &[(CODE_START + code.len() + 12) as u32, 0]);
+    put(&mut file, &[0; 10]);
+    file.extend(b"GLOBAL_DATA\0\0\0\0\0");
+    file.extend(b"CODE_START_\0");
+    file.extend(code);
+    file.extend(b"EXPORT_DATA\0");
+    file.resize(file.len().next_multiple_of(16), 0);
+    file
+}
+
+/// Lines あ, い, う and え, with translations of all but う.
+fn setup(name: &str) -> (Db, Vec<u32>) {
+    let db = Db::init(name);
+    let v1 = script(&[
+        (OP_LINE, Some(b"\x82\xa0")), (OP_YIELD, None),
+        (OP_LINE, Some(b"\x82\xa2")), (OP_YIELD, None),
+        (OP_LINE, Some(b"\x82\xa4")), (OP_YIELD, None),
+        (OP_LINE, Some(b"\x82\xa6")), (OP_YIELD, None)
+    ]);
+    Connection::open(&db).unwrap().execute("INSERT INTO scripts(id, script) VALUES (100, ?)", (v1,)).unwrap();
+    let out = blume(&db, &["stcm2", "analyze", "100"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    let conn = Connection::open(&db).unwrap();
+    let addrs = lines(&db).into_iter().map(|(addr, _)| addr).collect::<Vec<_>>();
+    assert_eq!(addrs.len(), 4);
+    for (addr, translation) in [(addrs[0], "Ah."), (addrs[1], "Eh."), (addrs[3], "Eeh.")] {
+        conn.execute("INSERT INTO translations(session, scriptid, address, translation) VALUES ('test', 100, ?, ?)", (addr, translation)).unwrap();
+    }
+
+    // あ becomes か, and an action before う moves it and え
+    let v2 = script(&[
+        (OP_LINE, Some(b"\x82\xa9")), (OP_YIELD, None),
+        (OP_LINE, Some(b"\x82\xa2")), (OP_YIELD, None),
+        (0x10, None),
+        (OP_LINE, Some(b"\x82\xa4")), (OP_YIELD, None),
+        (OP_LINE, Some(b"\x82\xa6")), (OP_YIELD, None)
+    ]);
+    conn.execute("UPDATE scripts SET script = ? WHERE id = 100", (v2,)).unwrap();
+    (db, addrs)
+}
+
+fn lines(db: &Db) -> Vec<(u32, String)> {
+    Connection::open(db).unwrap()
+        .prepare("SELECT address, line FROM lines WHERE scriptid = 100 ORDER BY address").unwrap()
+        .query_map((), |row| row.try_into()).unwrap()
+        .collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn reanalyze_keeps_only_translated_orphans() {
+    let (db, addrs) = setup("reanalyze");
+    let out = blume(&db, &["stcm2", "reanalyze", "100"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stdout(&out).contains("total: 1 changed, 2 added, 2 orphaned, 1 translations would be flagged as stale"), "{}", stdout(&out));
+    assert_eq!(lines(&db).len(), 4);
+
+    let out = blume(&db, &["stcm2", "reanalyze", "100", "--update"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(lines(&db), [
+        (addrs[0], "か".to_owned()),
+        (addrs[1], "い".to_owned()),
+        // う had no translation, so only the moved one is left
+        (addrs[2] + 16, "う".to_owned()),
+        (addrs[3], "え".to_owned()),
+        (addrs[3] + 16, "え".to_owned())
+    ]);
+    let stale = Connection::open(&db).unwrap()
+        .query_row("SELECT address, oldline FROM staletranslations WHERE session = 'test'", (), |row| <(u32, String)>::try_from(row))
+        .unwrap();
+    assert_eq!(stale, (addrs[0], "あ".to_owned()));
+}
+
+#[test]
+fn patch_after_reanalyze_reports_orphans() {
+    let (db, addrs) = setup("reanalyze-patch");
+    assert!(blume(&db, &["stcm2", "reanalyze", "100", "--update"]).status.success());
+
+    let out = blume(&db, &["stcm2", "patch", "100", "--sessions", "test"]);
+    assert!(out.status.success(), "{}{}", stdout(&out), stderr(&out));
+    let report = stdout(&out);
+    assert!(report.contains(&format!("{} ", addrs[3])) && report.contains("orphaned"), "{report}");
+    assert!(report.contains("100: 2 lines replaced"), "{report}");
+    assert!(report.contains(&format!("100 {}: test", addrs[1])), "{report}");
+
+    let out = blume(&db, &["stcm2", "patch", "100", "--sessions", "test", "--no-sources"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let report = stdout(&out);
+    assert!(report.contains("test: 3 entries") && !report.contains(&format!("100 {}: test", addrs[1])), "{report}");
+}
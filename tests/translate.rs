@@ -0,0 +1,225 @@
+//! Runs `blume translate` against the stand-in server in `fake`.
+
+mod common;
+mod fake;
+
+use std::path::Path;
+
+use rusqlite::Connection;
+
+use common::{blume, stderr, Db};
+use fake::{Fake, Reply};
+
+/// A database with one script: a spoken line, narration, a choice and a string that displays text.
+fn setup(name: &str) -> Db {
+    let db = Db::init(name);
+    Connection::open(&db).unwrap().execute_batch("
+        INSERT INTO scripts(id, script) VALUES (100, x'');
+        INSERT INTO lines(scriptid, address, speaker, line) VALUES
+            (100, 324, 'ダニエラ', '「おはよう」'),
+            (100, 516, '', '静かな朝だ。');
+        INSERT INTO choices(scriptid, address, prompt, choice, option) VALUES (100, 752, '', 0, '行く');
+        INSERT INTO strings(scriptid, address, param, opcode, call, text) VALUES (100, 900, 0, 0, 0, '地図');
+        INSERT INTO stringparams(opcode, call, param) VALUES (0, 0, 0);
+    ").unwrap();
+    db
+}
+
+// all translations of the session, lines first
+fn translations(db: &Path, session: &str) -> Vec<(u32, String)> {
+    Connection::open(db).unwrap().prepare("
+        SELECT address, translation FROM translations WHERE session = ?1
+        UNION ALL SELECT address, translation FROM choicetranslations WHERE session = ?1
+        UNION ALL SELECT address, translation FROM stringtranslations WHERE session = ?1
+    ").unwrap().query_map((session,), |row| row.try_into()).unwrap().collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn llm_translates_everything() {
+    let fake = Fake::start();
+    let db = setup("llm");
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url, "--session", "test"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    assert_eq!(translations(&db, "test"), [
+        (324, "Translated.".to_owned()),
+        (516, "Translated.".to_owned()),
+        (752, "Translated.".to_owned()),
+        (900, "Translated.".to_owned())
+    ]);
+    let completions = fake.requests("/completion");
+    assert_eq!(completions.len(), 4);
+    assert_eq!(completions[0]["grammar"], "root ::= \"[Daniela]: \" [^\\n]*");
+    assert!(completions[1].get("grammar").is_none());
+}
+
+#[test]
+fn llm_keeps_lines_before_truncated_completion() {
+    let fake = Fake::start();
+    fake.completion(Reply::Completion { content: "[Daniela]: Good morning.".to_owned(), truncated: false })
+        .completion(Reply::Completion { content: "It is a quiet morning and".to_owned(), truncated: true });
+    let db = setup("truncated");
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url, "--session", "test"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("maximum number of tokens reached"), "{}", stderr(&out));
+    assert_eq!(translations(&db, "test"), [(324, "Good morning.".to_owned())]);
+
+    // running again picks up where it stopped, with the earlier line as context
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url, "--session", "test"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(translations(&db, "test").len(), 4);
+    let tokenized = fake.requests("/tokenize");
+    assert!(tokenized.last().unwrap()["content"].as_str().unwrap().contains("[Daniela]: Good morning."));
+}
+
+#[test]
+fn llm_knows_renamed_player() {
+    let fake = Fake::start();
+    fake.completion(Reply::Completion { content: "[Heroine]: Heroine, it's me.".to_owned(), truncated: false });
+    let db = setup("player");
+    assert!(blume(&db, &["config", "player_name_jp", "ヒロイン"]).status.success());
+    assert!(blume(&db, &["config", "player_name_en", "Heroine"]).status.success());
+    Connection::open(&db).unwrap().execute(
+        "INSERT INTO lines(scriptid, address, speaker, line) VALUES (100, 200, '#Name[1]', '「#Name[1]、私よ」')", ()
+    ).unwrap();
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url, "--session", "test"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    assert_eq!(translations(&db, "test")[0], (200, "#Name[1], it's me.".to_owned()));
+    assert_eq!(fake.requests("/completion")[0]["grammar"], "root ::= \"[Heroine]: \" [^\\n]*");
+}
+
+#[test]
+fn llm_fails_when_prompt_does_not_fit() {
+    let fake = Fake::start();
+    let db = setup("context");
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url, "--context", "60"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("without any context"), "{}", stderr(&out));
+    assert!(fake.requests("/completion").is_empty());
+}
+
+#[test]
+fn llm_completions_api_counts_tokens_without_tokenize() {
+    let fake = Fake::start();
+    // vLLM and most openai-compatible servers have no tokenize endpoint
+    fake.tokenize(Reply::Status(404));
+    let db = setup("completions");
+    assert!(blume(&db, &["config", "llm_api_key", "test-key"]).status.success());
+    let endpoint = format!("{}/v1", fake.url);
+    let out = blume(&db, &["translate", "llm", "100", "--api", "completions", "--endpoint", &endpoint, "--model", "test-model", "--session", "test"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stderr(&out).contains("counting tokens approximately"), "{}", stderr(&out));
+
+    assert_eq!(translations(&db, "test").len(), 4);
+    assert!(translations(&db, "test").iter().all(|(_, t)| t == "Translated."));
+    assert_eq!(fake.requests("/tokenize").len(), 1);
+    let completions = fake.requests("/v1/completions");
+    assert_eq!(completions.len(), 4);
+    assert_eq!(completions[0]["model"], "test-model");
+    assert!(completions[0]["prompt"].as_str().unwrap().ends_with("[Daniela]: "));
+    assert!(fake.authorizations("/v1/completions").iter().all(|a| a.as_deref() == Some("Bearer test-key")));
+}
+
+#[test]
+fn llm_chat_api_answers_for_the_speaker() {
+    let fake = Fake::start();
+    fake.tokenize(Reply::Status(404));
+    let db = setup("chat");
+    assert!(blume(&db, &["config", "llm_api_key", "test-key"]).status.success());
+    let endpoint = format!("{}/v1", fake.url);
+    let out = blume(&db, &["translate", "llm", "100", "--api", "chat", "--endpoint", &endpoint, "--session", "test"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    assert_eq!(translations(&db, "test").len(), 4);
+    assert!(translations(&db, "test").iter().all(|(_, t)| t == "Translated."));
+    let chats = fake.requests("/v1/chat/completions");
+    assert_eq!(chats.len(), 4);
+    assert!(chats[0]["messages"][0]["content"].as_str().unwrap().contains("starting with `[Daniela]: `"));
+    assert_eq!(chats[0]["messages"][1]["role"], "user");
+    assert!(fake.authorizations("/v1/chat/completions").iter().all(|a| a.as_deref() == Some("Bearer test-key")));
+}
+
+#[test]
+fn llm_fails_on_server_errors() {
+    let fake = Fake::start();
+    fake.tokenize(Reply::Status(503));
+    let db = setup("tokenize-error");
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("503"), "{}", stderr(&out));
+    assert!(fake.requests("/completion").is_empty());
+
+    // only a missing tokenize endpoint falls back to counting approximately
+    fake.tokenize(Reply::Status(503));
+    let endpoint = format!("{}/v1", fake.url);
+    let out = blume(&db, &["translate", "llm", "100", "--api", "completions", "--endpoint", &endpoint]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("503") && !stderr(&out).contains("approximately"), "{}", stderr(&out));
+    assert!(fake.requests("/v1/completions").is_empty());
+
+    fake.completion(Reply::Status(500));
+    let out = blume(&db, &["translate", "llm", "100", "--endpoint", &fake.url]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("500"), "{}", stderr(&out));
+    assert!(translations(&db, "vntl-greedy-20240823").is_empty());
+}
+
+fn setup_google(name: &str, fake: &Fake) -> Db {
+    let db = setup(name);
+    assert!(blume(&db, &["config", "google_api_key", "test-key"]).status.success());
+    assert!(blume(&db, &["config", "google_endpoint", &fake.url]).status.success());
+    db
+}
+
+#[test]
+fn google_translates_in_batches() {
+    let fake = Fake::start();
+    let db = setup_google("google", &fake);
+    let out = blume(&db, &["translate", "google", "100"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+
+    assert_eq!(translations(&db, "google").len(), 4);
+    let requests = fake.requests("/language/translate/v2");
+    assert_eq!(requests.len(), 3, "lines, choices and strings");
+    assert_eq!(requests[0]["q"], serde_json::json!(["「おはよう」", "静かな朝だ。"]));
+    assert_eq!(requests[0]["source"], "ja");
+}
+
+#[test]
+fn google_skips_missing_translations() {
+    let fake = Fake::start();
+    fake.google(Reply::Google(vec![Some("\"Good morning.\"".to_owned()), None]));
+    let db = setup_google("google-missing", &fake);
+    let out = blume(&db, &["translate", "google", "100"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(stderr(&out).contains("addr 516 has no translation"), "{}", stderr(&out));
+
+    let translations = translations(&db, "google");
+    assert_eq!(translations[0], (324, "\"Good morning.\"".to_owned()));
+    assert!(!translations.iter().any(|&(address, _)| address == 516));
+}
+
+#[test]
+fn google_fails_on_error_status() {
+    let fake = Fake::start();
+    fake.google(Reply::Status(403));
+    let db = setup_google("google-error", &fake);
+    let out = blume(&db, &["translate", "google", "100"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("bad response"), "{}", stderr(&out));
+    assert!(translations(&db, "google").is_empty());
+}
+
+#[test]
+fn mock_needs_no_server() {
+    let db = setup("mock");
+    let out = blume(&db, &["translate", "mock", "100"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(translations(&db, "mock"), [
+        (324, "Line 324 has 6 characters.".to_owned()),
+        (516, "Line 516 has 6 characters.".to_owned()),
+        (752, "Choice 752".to_owned()),
+        (900, "String 900.0".to_owned())
+    ]);
+}